@@ -332,7 +332,7 @@ fn new_piece_location_ok(x_test: i32, y_test: i32) -> bool {
         let y = (c >> 2) + y_test;
         let x = (c & 3) + x_test;
         // check if we are out of the play field
-        if y < 0 || y >= PLAYFIELD_H || x < 0 || x >= PLAYFIELD_W {
+        if !(0..PLAYFIELD_H).contains(&y) || !(0..PLAYFIELD_W).contains(&x) {
             return false;
         }
         // check if location is already ocupied
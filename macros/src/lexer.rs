@@ -263,10 +263,8 @@ impl Lexer {
 
         // expect new command
         match self.iter.peek() {
-            Some(TokenTree::Punct(np)) => {
-                if np.as_char() == '|' || np.as_char() == ';' {
-                    abort!(np.span(), "expect new command after '|'");
-                }
+            Some(TokenTree::Punct(np)) if np.as_char() == '|' || np.as_char() == ';' => {
+                abort!(np.span(), "expect new command after '|'");
             }
             None => {
                 abort!(self.iter.span(), "expect new command after '|'");
@@ -81,7 +81,7 @@ pub fn use_custom_cmd(item: proc_macro::TokenStream) -> proc_macro::TokenStream
 
 /// Run commands, returning [`CmdResult`](../cmd_lib/type.CmdResult.html) to check status.
 /// ```no_run
-/// # use cmd_lib::run_cmd;
+/// # use cmd_lib::{run_cmd, Shell};
 /// let msg = "I love rust";
 /// run_cmd!(echo $msg)?;
 /// run_cmd!(echo "This is the message: $msg")?;
@@ -103,17 +103,17 @@ pub fn use_custom_cmd(item: proc_macro::TokenStream) -> proc_macro::TokenStream
 /// }.is_err() {
 ///     // your error handling code
 /// }
+///
+/// // or in a shell session, which keeps its current directory
+/// let mut sh = Shell::new();
+/// run_cmd!(in sh; cd /tmp)?;
+/// run_cmd!(in sh; ls)?;
 /// # Ok::<(), std::io::Error>(())
 /// ```
 #[proc_macro]
 #[proc_macro_error]
 pub fn run_cmd(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let cmds = lexer::Lexer::new(input.into()).scan().parse(false);
-    quote! ({
-        use ::cmd_lib::AsOsStr;
-        #cmds.run_cmd()
-    })
-    .into()
+    run_cmds(input.into(), false, quote!(run_cmd), quote!())
 }
 
 /// Run commands, returning [`FunResult`](../cmd_lib/type.FunResult.html) to capture output and to check status.
@@ -130,15 +130,13 @@ pub fn run_cmd(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 #[proc_macro]
 #[proc_macro_error]
 pub fn run_fun(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let cmds = lexer::Lexer::new(input.into()).scan().parse(false);
-    quote! ({
-        use ::cmd_lib::AsOsStr;
-        #cmds.run_fun()
-    })
-    .into()
+    run_cmds(input.into(), false, quote!(run_fun), quote!())
 }
 
 /// Run commands with/without pipes as a child process, returning [`CmdChildren`](../cmd_lib/struct.CmdChildren.html) result.
+///
+/// The processes run in their own process group, so they can be signaled as a whole. Notice that
+/// they are not part of the terminal's foreground job, so they won't get Ctrl-C from it.
 /// ```no_run
 /// # use cmd_lib::*;
 ///
@@ -151,12 +149,7 @@ pub fn run_fun(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 #[proc_macro]
 #[proc_macro_error]
 pub fn spawn(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let cmds = lexer::Lexer::new(input.into()).scan().parse(true);
-    quote! ({
-        use ::cmd_lib::AsOsStr;
-        #cmds.spawn(false)
-    })
-    .into()
+    run_cmds(input.into(), true, quote!(spawn), quote!(false))
 }
 
 /// Run commands with/without pipes as a child process, returning [`FunChildren`](../cmd_lib/struct.FunChildren.html) result.
@@ -180,12 +173,77 @@ pub fn spawn(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 #[proc_macro]
 #[proc_macro_error]
 pub fn spawn_with_output(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let cmds = lexer::Lexer::new(input.into()).scan().parse(true);
-    quote! ({
-        use ::cmd_lib::AsOsStr;
-        #cmds.spawn_with_output()
-    })
-    .into()
+    run_cmds(input.into(), true, quote!(spawn_with_output), quote!())
+}
+
+/// Asynchronous version of [`run_cmd!`](../cmd_lib/macro.run_cmd.html), returning a future of
+/// [`CmdResult`](../cmd_lib/type.CmdResult.html). Requires the `async` feature.
+/// ```no_run
+/// # use cmd_lib::*;
+/// # async fn f() -> CmdResult {
+/// run_cmd_async!(du -ah . | sort -hr | head -n 10).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[proc_macro]
+#[proc_macro_error]
+pub fn run_cmd_async(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    run_cmds(input.into(), false, quote!(run_cmd_async), quote!())
+}
+
+/// Asynchronous version of [`run_fun!`](../cmd_lib/macro.run_fun.html), returning a future of
+/// [`FunResult`](../cmd_lib/type.FunResult.html). Requires the `async` feature.
+/// ```no_run
+/// # use cmd_lib::*;
+/// # async fn f() -> CmdResult {
+/// let version = run_fun_async!(rustc --version).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[proc_macro]
+#[proc_macro_error]
+pub fn run_fun_async(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    run_cmds(input.into(), false, quote!(run_fun_async), quote!())
+}
+
+/// Asynchronous version of [`spawn!`](../cmd_lib/macro.spawn.html), returning
+/// [`AsyncCmdChildren`](../cmd_lib/struct.AsyncCmdChildren.html) result. Requires the `async`
+/// feature, and must be called within a tokio runtime.
+/// ```no_run
+/// # use cmd_lib::*;
+/// # async fn f() -> CmdResult {
+/// let mut handle = spawn_async!(ping -c 10 192.168.0.1)?;
+/// // ...
+/// handle.wait().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[proc_macro]
+#[proc_macro_error]
+pub fn spawn_async(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    run_cmds(input.into(), true, quote!(spawn_async), quote!(false))
+}
+
+/// Asynchronous version of [`spawn_with_output!`](../cmd_lib/macro.spawn_with_output.html),
+/// returning [`AsyncFunChildren`](../cmd_lib/struct.AsyncFunChildren.html) result. Requires
+/// the `async` feature, and must be called within a tokio runtime.
+/// ```no_run
+/// # use cmd_lib::*;
+/// # async fn f() -> CmdResult {
+/// let mut proc = spawn_with_output_async!(seq 1 10 | wc -l)?;
+/// let n = proc.wait_with_output().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[proc_macro]
+#[proc_macro_error]
+pub fn spawn_with_output_async(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    run_cmds(
+        input.into(),
+        true,
+        quote!(spawn_with_output_async),
+        quote!(),
+    )
 }
 
 #[proc_macro]
@@ -213,6 +271,45 @@ pub fn cmd_die(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     .into()
 }
 
+// Calls `method` on the parsed commands, or on the shell given with an `in <shell>;` prefix
+fn run_cmds(
+    input: TokenStream,
+    for_spawn: bool,
+    method: TokenStream,
+    args: TokenStream,
+) -> proc_macro::TokenStream {
+    let (shell, input) = split_shell(input);
+    let cmds = lexer::Lexer::new(input).scan().parse(for_spawn);
+    let call = match shell {
+        Some(shell) => quote!((#shell).#method(#cmds, #args)),
+        None => quote!(#cmds.#method(#args)),
+    };
+    quote! ({
+        use ::cmd_lib::AsOsStr;
+        #call
+    })
+    .into()
+}
+
+fn split_shell(input: TokenStream) -> (Option<TokenStream>, TokenStream) {
+    let mut iter = input.clone().into_iter();
+    match iter.next() {
+        Some(TokenTree::Ident(ident)) if ident == "in" => {}
+        _ => return (None, input),
+    }
+    let mut shell = TokenStream::new();
+    for tt in iter.by_ref() {
+        if matches!(tt, TokenTree::Punct(ref p) if p.as_char() == ';') {
+            if shell.is_empty() {
+                abort!(tt, "expect a shell after `in`");
+            }
+            return (Some(shell), iter.collect());
+        }
+        shell.extend([tt]);
+    }
+    abort!(input, "expect `;` after the shell given with `in`");
+}
+
 fn parse_msg(input: TokenStream) -> TokenStream {
     let mut iter = input.into_iter();
     let mut output = TokenStream::new();
@@ -0,0 +1,445 @@
+use crate::child::{
+    copy_result, output_to_string, send_signal, timed_out_error, CmdChildHandle, LineFormat,
+    Signal, StderrOutput, StderrSink, KILL_GRACE_PERIOD,
+};
+use crate::io::CancelToken;
+use crate::process::{self, CmdTask, PreparedCmd};
+use crate::{CmdResult, FunResult};
+use os_pipe::PipeReader;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::OwnedFd;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::unix::pipe::Receiver;
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+
+/// Representation of running or exited children processes within a tokio runtime, connected
+/// with pipes optionally.
+///
+/// Calling [`spawn_async!`](../cmd_lib/macro.spawn_async.html) macro will return
+/// `Result<AsyncCmdChildren>`
+pub struct AsyncCmdChildren {
+    children: Vec<AsyncCmdChild>,
+    ignore_error: bool,
+    cmd: String,
+    file: String,
+    line: u32,
+    timeout: Option<Duration>,
+    pgid: Option<u32>,
+    result: Option<CmdResult>,
+}
+
+impl AsyncCmdChildren {
+    pub(crate) fn new(
+        children: Vec<AsyncCmdChild>,
+        ignore_error: bool,
+        cmd: String,
+        file: String,
+        line: u32,
+        timeout: Option<Duration>,
+        pgid: Option<u32>,
+    ) -> Self {
+        Self {
+            children,
+            ignore_error,
+            cmd,
+            file,
+            line,
+            timeout,
+            pgid,
+            result: None,
+        }
+    }
+
+    // Fails on errors of any command, or only on errors of the last one
+    pub(crate) fn pipefail(mut self, enable: bool) -> Self {
+        for child in self.children.iter_mut() {
+            child.pipefail = enable;
+        }
+        self
+    }
+
+    pub(crate) fn into_fun_children(self) -> AsyncFunChildren {
+        AsyncFunChildren { inner: self }
+    }
+
+    /// Waits for the children processes to exit completely, returning the status that they exited with.
+    ///
+    /// If the children were spawned with a `timeout` prefix, they are terminated once it expires
+    /// and an error of kind [`ErrorKind::TimedOut`] is returned, like with
+    /// [`CmdChildren::wait`](crate::CmdChildren::wait).
+    ///
+    /// Once the children were waited for, the same result is returned again.
+    pub async fn wait(&mut self) -> CmdResult {
+        if let Some(res) = self.result.as_ref() {
+            return copy_result(res);
+        }
+        let res = match self.timeout {
+            None => self.inner_wait().await,
+            Some(duration) => match tokio::time::timeout(duration, self.inner_wait()).await {
+                Ok(res) => res,
+                Err(_) => Err(self.expire(duration).await),
+            },
+        };
+        self.result = Some(copy_result(&res));
+        res
+    }
+
+    async fn inner_wait(&mut self) -> CmdResult {
+        // wait for the last child result
+        let Some((last, others)) = self.children.split_last_mut() else {
+            return Ok(());
+        };
+        let mut ret = last.wait(true).await;
+        for child in others.iter_mut().rev() {
+            if let Err(e) = child.wait(false).await {
+                ret = ret.and(Err(e));
+            }
+        }
+        self.children.clear();
+        ret
+    }
+
+    // Sends SIGTERM to the children, and SIGKILL to the ones still running after a grace period
+    async fn expire(&mut self, duration: Duration) -> Error {
+        if let Some(pgid) = self.pgid {
+            if self.children.iter().any(|child| child.pid().is_some()) {
+                let _ = send_signal(-(pgid as i32), Signal::Term);
+            }
+        }
+        for child in self.children.iter_mut() {
+            child.terminate(self.pgid.is_none());
+        }
+        if tokio::time::timeout(KILL_GRACE_PERIOD, self.inner_wait())
+            .await
+            .is_err()
+        {
+            let _ = self.kill();
+        }
+        timed_out_error(&self.cmd, duration, &self.file, self.line)
+    }
+
+    /// Forces the children processes to exit.
+    ///
+    /// See [`CmdChildren::kill`](crate::CmdChildren::kill) for which processes are killed.
+    /// Killed processes are reaped by the runtime in the background.
+    pub fn kill(&mut self) -> CmdResult {
+        let mut ret = Ok(());
+        if let Some(pgid) = self.pgid {
+            ret = send_signal(-(pgid as i32), Signal::Kill);
+        }
+        for mut child in self.children.drain(..) {
+            if let Err(e) = child.kill() {
+                ret = Err(e);
+            }
+        }
+        ret
+    }
+
+    /// Returns the OS-assigned process identifiers associated with these children processes.
+    pub fn pids(&self) -> Vec<u32> {
+        self.children.iter().filter_map(|x| x.pid()).collect()
+    }
+}
+
+/// Representation of running or exited children processes with output within a tokio runtime,
+/// connected with pipes optionally.
+///
+/// Calling [`spawn_with_output_async!`](../cmd_lib/macro.spawn_with_output_async.html) macro
+/// will return `Result<AsyncFunChildren>`
+pub struct AsyncFunChildren {
+    inner: AsyncCmdChildren,
+}
+
+impl AsyncFunChildren {
+    /// Waits for the children processes to exit completely, returning the command result, stdout
+    /// content string and stderr content string.
+    pub async fn wait_with_all(&mut self) -> (CmdResult, String, String) {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let res = match self.inner.timeout {
+            None => {
+                self.inner_wait_with_all(true, &mut stdout, &mut stderr)
+                    .await
+            }
+            Some(duration) => {
+                let wait = self.inner_wait_with_all(true, &mut stdout, &mut stderr);
+                match tokio::time::timeout(duration, wait).await {
+                    Ok(res) => res,
+                    Err(_) => Err(self.inner.expire(duration).await),
+                }
+            }
+        };
+        (res, output_to_string(stdout), output_to_string(stderr))
+    }
+
+    /// Waits for the children processes to exit completely, returning the stdout output.
+    pub async fn wait_with_output(&mut self) -> FunResult {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let res = match self.inner.timeout {
+            None => {
+                self.inner_wait_with_all(false, &mut stdout, &mut stderr)
+                    .await
+            }
+            Some(duration) => {
+                let wait = self.inner_wait_with_all(false, &mut stdout, &mut stderr);
+                match tokio::time::timeout(duration, wait).await {
+                    Ok(res) => res,
+                    Err(_) => Err(self.inner.expire(duration).await),
+                }
+            }
+        };
+        if let Err(e) = res {
+            if !self.inner.ignore_error {
+                return Err(e);
+            }
+        }
+        Ok(output_to_string(stdout))
+    }
+
+    /// Forces the children processes to exit.
+    ///
+    /// See [`AsyncCmdChildren::kill`] for which processes are killed.
+    pub fn kill(&mut self) -> CmdResult {
+        self.inner.kill()
+    }
+
+    /// Returns the OS-assigned process identifiers associated with these children processes.
+    pub fn pids(&self) -> Vec<u32> {
+        self.inner.pids()
+    }
+
+    async fn inner_wait_with_all(
+        &mut self,
+        capture_stderr: bool,
+        stdout: &mut Vec<u8>,
+        stderr: &mut Vec<u8>,
+    ) -> CmdResult {
+        // wait for the last child result
+        let Some((last, others)) = self.inner.children.split_last_mut() else {
+            return Ok(());
+        };
+        let res = last.wait_with_all(capture_stderr, stdout, stderr).await;
+        for child in others.iter_mut().rev() {
+            let _ = child.wait(false).await;
+        }
+        self.inner.children.clear();
+        res
+    }
+}
+
+pub(crate) struct AsyncCmdChild {
+    handle: AsyncCmdChildHandle,
+    cmd: String,
+    name: String,
+    file: String,
+    line: u32,
+    stdout: Option<Receiver>,
+    stderr: Option<PipeReader>,
+    stderr_task: Option<JoinHandle<StderrOutput>>,
+    stderr_tail: usize,
+    stderr_sink: StderrSink,
+    stderr_format: LineFormat,
+    pipefail: bool,
+}
+
+impl AsyncCmdChild {
+    // Starts a prepared command, running custom commands on tokio's blocking thread pool
+    pub(crate) fn spawn(prepared: PreparedCmd) -> Result<Self> {
+        let handle = match prepared.task {
+            CmdTask::Done => AsyncCmdChildHandle::Done,
+            CmdTask::Builtin(internal_cmd, mut env) => {
+                let cancel = CancelToken::default();
+                env.set_cancel(&cancel);
+                let task = tokio::task::spawn_blocking(move || internal_cmd(&mut env));
+                AsyncCmdChildHandle::Task(task, cancel)
+            }
+            CmdTask::Proc(cmd) => AsyncCmdChildHandle::Proc(Command::from(cmd).spawn()?),
+        };
+        let stdout = match prepared.stdout {
+            Some(stdout) => Some(Receiver::from_owned_fd(OwnedFd::from(stdout))?),
+            None => None,
+        };
+        Ok(Self {
+            handle,
+            cmd: prepared.cmd_str,
+            name: prepared.name,
+            file: prepared.file,
+            line: prepared.line,
+            stdout,
+            stderr: prepared.stderr,
+            stderr_task: None,
+            stderr_tail: process::stderr_tail_lines(),
+            stderr_sink: process::stderr_sink(),
+            stderr_format: LineFormat::default(),
+            pipefail: process::pipefail_enabled(),
+        })
+    }
+
+    async fn wait(&mut self, is_last: bool) -> CmdResult {
+        self.start_stderr_task(false)?;
+        let res = self.handle.wait(&self.cmd, &self.file, self.line).await;
+        let stderr_output = self.join_stderr_task().await;
+        if let Err(e) = res {
+            if is_last || self.pipefail {
+                return Err(stderr_output.attach_tail(e));
+            }
+        }
+        Ok(())
+    }
+
+    async fn wait_with_all(
+        &mut self,
+        capture_stderr: bool,
+        stdout_buf: &mut Vec<u8>,
+        stderr_buf: &mut Vec<u8>,
+    ) -> CmdResult {
+        self.start_stderr_task(capture_stderr)?;
+        let mut stdout_res = Ok(());
+        if let Some(stdout) = self.stdout.as_mut() {
+            if let Err(e) = stdout.read_to_end(stdout_buf).await {
+                stdout_res = Err(e)
+            }
+        }
+        let stderr_output = self.join_stderr_task().await;
+        let wait_res = self
+            .handle
+            .wait(&self.cmd, &self.file, self.line)
+            .await
+            .map_err(|e| stderr_output.attach_tail(e));
+        *stderr_buf = stderr_output.captured;
+        wait_res.and(stdout_res)
+    }
+
+    // Forwards stderr line by line from a task, like `StderrThread` does for the blocking API
+    fn start_stderr_task(&mut self, capture: bool) -> Result<()> {
+        if let Some(stderr) = self.stderr.take() {
+            let stderr = Receiver::from_owned_fd(OwnedFd::from(stderr))?;
+            let tail_lines = self.stderr_tail;
+            let sink = if capture {
+                None
+            } else {
+                Some(self.stderr_sink.clone())
+            };
+            let format = self.stderr_format.resolve(&self.name, self.pid());
+            let cmd = self.cmd.clone();
+            self.stderr_task = Some(tokio::spawn(async move {
+                let mut output = StderrOutput::default();
+                let mut stderr = BufReader::new(stderr);
+                let mut buf = vec![];
+                while let Ok(n) = stderr.read_until(b'\n', &mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                    output.push_line(&cmd, &buf, sink.as_ref(), &format, tail_lines);
+                    buf.clear();
+                }
+                output
+            }));
+        }
+        Ok(())
+    }
+
+    async fn join_stderr_task(&mut self) -> StderrOutput {
+        if let Some(task) = self.stderr_task.as_mut() {
+            let res = task.await;
+            self.stderr_task = None;
+            match res {
+                Ok(output) => return output,
+                Err(e) => {
+                    crate::warn!(
+                        "Running [{}] stderr task joined with error: {e} at {}:{}",
+                        self.cmd,
+                        self.file,
+                        self.line
+                    );
+                }
+            }
+        }
+        StderrOutput::default()
+    }
+
+    fn kill(&mut self) -> CmdResult {
+        self.handle.kill(&self.cmd, &self.file, self.line)
+    }
+
+    // Asks the command to exit, cancelling custom commands, and with SIGTERM for processes
+    // unless their group was signaled already
+    fn terminate(&mut self, signal_proc: bool) {
+        match &self.handle {
+            AsyncCmdChildHandle::Proc(proc) if signal_proc => {
+                if let Some(pid) = proc.id() {
+                    let _ = send_signal(pid as i32, Signal::Term);
+                }
+            }
+            AsyncCmdChildHandle::Task(_, cancel) => cancel.cancel(),
+            AsyncCmdChildHandle::Proc(_) | AsyncCmdChildHandle::Done => {}
+        }
+    }
+
+    pub(crate) fn pid(&self) -> Option<u32> {
+        match &self.handle {
+            AsyncCmdChildHandle::Proc(proc) => proc.id(),
+            _ => None,
+        }
+    }
+}
+
+enum AsyncCmdChildHandle {
+    Proc(Child),
+    Task(JoinHandle<CmdResult>, CancelToken),
+    Done,
+}
+
+impl AsyncCmdChildHandle {
+    async fn wait(&mut self, cmd: &str, file: &str, line: u32) -> CmdResult {
+        match self {
+            AsyncCmdChildHandle::Proc(proc) => {
+                let status = proc
+                    .wait()
+                    .await
+                    .map_err(|e| process::new_cmd_io_error(&e, cmd, file, line))?;
+                if !status.success() {
+                    return Err(CmdChildHandle::status_to_io_error(status, cmd, file, line));
+                }
+                Ok(())
+            }
+            AsyncCmdChildHandle::Task(task, _) => {
+                let res = task.await;
+                // a finished task can't be awaited again
+                *self = AsyncCmdChildHandle::Done;
+                match res {
+                    Ok(result) => {
+                        result.map_err(|e| process::new_cmd_io_error(&e, cmd, file, line))
+                    }
+                    Err(e) => Err(Error::other(format!(
+                        "Running [{cmd}] task joined with error: {e} at {file}:{line}"
+                    ))),
+                }
+            }
+            AsyncCmdChildHandle::Done => Ok(()),
+        }
+    }
+
+    fn kill(&mut self, cmd: &str, file: &str, line: u32) -> CmdResult {
+        match self {
+            AsyncCmdChildHandle::Proc(proc) => match proc.start_kill() {
+                // already exited and reaped
+                Err(e) if e.kind() == ErrorKind::InvalidInput => Ok(()),
+                res => res.map_err(|e| {
+                    Error::new(
+                        e.kind(),
+                        format!("Killing process [{cmd}] failed with error: {e} at {file}:{line}"),
+                    )
+                }),
+            },
+            AsyncCmdChildHandle::Task(_, cancel) => {
+                cancel.cancel();
+                Ok(())
+            }
+            AsyncCmdChildHandle::Done => Ok(()),
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use crate::{debug, error, info, trace, warn};
 use crate::{CmdEnv, CmdResult};
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
 
 pub(crate) fn builtin_echo(env: &mut CmdEnv) -> CmdResult {
@@ -38,9 +40,67 @@ pub(crate) fn builtin_trace(env: &mut CmdEnv) -> CmdResult {
     Ok(())
 }
 
+pub(crate) fn builtin_dry_run(env: &mut CmdEnv) -> CmdResult {
+    std::io::copy(env.stdin(), &mut std::io::sink())?;
+    Ok(())
+}
+
 pub(crate) fn builtin_empty(env: &mut CmdEnv) -> CmdResult {
     let mut buf = vec![];
     env.stdin().read_to_end(&mut buf)?;
     env.stdout().write_all(&buf)?;
     Ok(())
 }
+
+// Returns whether the `tee` builtin supports the options, or the external `tee` should be run
+pub(crate) fn tee_supports(args: &[OsString]) -> bool {
+    for arg in args {
+        let arg = arg.to_string_lossy();
+        if arg == "--" || !arg.starts_with('-') || arg == "-" {
+            break;
+        }
+        if arg != "-a" && arg != "--append" {
+            return false;
+        }
+    }
+    true
+}
+
+pub(crate) fn builtin_tee(env: &mut CmdEnv) -> CmdResult {
+    let args = env.get_args();
+    let mut append = false;
+    let mut paths = args;
+    while let Some((arg, rest)) = paths.split_first() {
+        match arg.as_str() {
+            "-a" | "--append" => append = true,
+            "--" => {
+                paths = rest;
+                break;
+            }
+            _ => break,
+        }
+        paths = rest;
+    }
+    let mut files = vec![];
+    for path in paths {
+        let path = env.current_dir().join(path);
+        let file = if append {
+            OpenOptions::new().create(true).append(true).open(path)?
+        } else {
+            File::create(path)?
+        };
+        files.push(file);
+    }
+
+    let mut buf = [0; 8192];
+    loop {
+        let n = env.stdin().read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        env.stdout().write_all(&buf[..n])?;
+        for file in files.iter_mut() {
+            file.write_all(&buf[..n])?;
+        }
+    }
+}
@@ -1,9 +1,27 @@
+use crate::io::CancelToken;
 use crate::{info, warn};
 use crate::{process, CmdResult, FunResult};
 use os_pipe::PipeReader;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
 use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+// How long children get to exit after SIGTERM, before being killed with SIGKILL
+pub(crate) const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+// Held while reaping children, so that timeouts never signal a pid which was reused meanwhile
+static REAPING: Mutex<()> = Mutex::new(());
 
 /// Representation of running or exited children processes, connected with pipes
 /// optionally.
@@ -12,27 +30,114 @@ use std::thread::JoinHandle;
 pub struct CmdChildren {
     children: Vec<CmdChild>,
     ignore_error: bool,
+    cmd: String,
+    file: String,
+    line: u32,
+    timeout: Option<CmdTimeout>,
+    pgid: Option<u32>,
+    kill_on_drop: bool,
+    usage: Arc<Mutex<UsageRecorder>>,
+    // result of the first completed wait, returned again by later ones
+    result: Option<CmdResult>,
 }
 
 impl CmdChildren {
-    pub(crate) fn new(children: Vec<CmdChild>, ignore_error: bool) -> Self {
+    pub(crate) fn new(
+        mut children: Vec<CmdChild>,
+        ignore_error: bool,
+        cmd: String,
+        file: String,
+        line: u32,
+        timeout: Option<Duration>,
+        pgid: Option<u32>,
+    ) -> Self {
+        let usage = Arc::new(Mutex::new(UsageRecorder::new(cmd.clone(), children.len())));
+        for (i, child) in children.iter_mut().enumerate() {
+            child.usage = usage.clone();
+            child.stage = i;
+        }
+        if children.len() > 1 {
+            watch_exits(&usage, &children);
+        }
         Self {
             children,
             ignore_error,
+            cmd,
+            file,
+            line,
+            timeout: timeout.map(CmdTimeout::new),
+            pgid,
+            kill_on_drop: false,
+            usage,
+            result: None,
         }
     }
 
-    pub(crate) fn into_fun_children(self) -> FunChildren {
-        FunChildren {
-            children: self.children,
-            ignore_error: self.ignore_error,
+    // Logs the timing of the whole pipeline once it finished, for the `time` prefix
+    pub(crate) fn report_time(self, enable: bool) -> Self {
+        self.usage.lock().unwrap().report = enable;
+        self
+    }
+
+    // Fails on errors of any command, or only on errors of the last one
+    pub(crate) fn pipefail(mut self, enable: bool) -> Self {
+        for child in self.children.iter_mut() {
+            child.pipefail = enable;
         }
+        self
+    }
+
+    // Traces the status and duration of the pipeline once it finished
+    pub(crate) fn xtrace(self, xtrace: Xtrace) -> Self {
+        self.usage.lock().unwrap().xtrace = xtrace;
+        self
+    }
+
+    // Records the children in the span of the pipeline, which is closed once they all finished
+    #[cfg(feature = "tracing")]
+    pub(crate) fn trace(self, span: tracing::Span) -> Self {
+        span.record("pids", format!("{:?}", self.pids()));
+        self.usage.lock().unwrap().open_span(span);
+        self
+    }
+
+    pub(crate) fn into_fun_children(self) -> FunChildren {
+        FunChildren { inner: self }
     }
 
     /// Waits for the children processes to exit completely, returning the status that they exited with.
+    ///
+    /// If the children were spawned with a `timeout` prefix, they are terminated once it expires
+    /// and an error of kind [`ErrorKind::TimedOut`] is returned.
+    ///
+    /// Once the children were waited for, the same result is returned again.
     pub fn wait(&mut self) -> CmdResult {
+        if let Some(res) = self.result.as_ref() {
+            return copy_result(res);
+        }
+        let watchdog = self.start_watchdog();
+        let res = self.inner_wait();
+        let res = Watchdog::check(watchdog, res);
+        self.result = Some(copy_result(&res));
+        res
+    }
+
+    /// Waits for the children processes to exit completely, terminating them if they are still
+    /// running after `timeout`.
+    ///
+    /// On expiry every process of the pipeline gets SIGTERM, followed by SIGKILL if it is still
+    /// alive after a grace period, custom commands are cancelled, and an error of kind
+    /// [`ErrorKind::TimedOut`] is returned.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> CmdResult {
+        self.timeout = Some(CmdTimeout::new(timeout));
+        self.wait()
+    }
+
+    fn inner_wait(&mut self) -> CmdResult {
         // wait for the last child result
-        let handle = self.children.pop().unwrap();
+        let Some(handle) = self.children.pop() else {
+            return Ok(());
+        };
         if let Err(e) = handle.wait(true) {
             let _ = Self::wait_children(&mut self.children);
             return Err(e);
@@ -40,6 +145,20 @@ impl CmdChildren {
         Self::wait_children(&mut self.children)
     }
 
+    fn start_watchdog(&self) -> Option<Watchdog> {
+        self.timeout.as_ref().map(|timeout| {
+            Watchdog::start(
+                timeout,
+                self.pgid,
+                self.pids(),
+                self.cancel_tokens(),
+                &self.cmd,
+                &self.file,
+                self.line,
+            )
+        })
+    }
+
     fn wait_children(children: &mut Vec<CmdChild>) -> CmdResult {
         let mut ret = Ok(());
         while let Some(child_handle) = children.pop() {
@@ -50,21 +169,207 @@ impl CmdChildren {
         ret
     }
 
+    /// Checks whether the children processes have all exited, without blocking.
+    ///
+    /// Returns `None` if any of them is still running. Otherwise the children are waited for,
+    /// and their result is returned the same way as [`wait`](Self::wait) does, including on
+    /// later calls.
+    pub fn try_wait(&mut self) -> Option<CmdResult> {
+        if self.statuses().contains(&StageStatus::Running) {
+            return None;
+        }
+        Some(self.wait())
+    }
+
+    /// Waits for the children processes to exit completely, returning the outcome of each
+    /// command in the pipeline, in order.
+    ///
+    /// Unlike [`wait`](Self::wait), no stage is treated as a failure, which leaves the policy,
+    /// like failing only if the producer failed, to the caller.
+    pub fn wait_all(&mut self) -> Vec<StageOutcome> {
+        let watchdog = self.start_watchdog();
+        let mut outcomes: Vec<_> = std::iter::from_fn(|| self.children.pop())
+            .map(CmdChild::wait_outcome)
+            .collect();
+        outcomes.reverse();
+        let _ = Watchdog::check(watchdog, Ok(()));
+        outcomes
+    }
+
+    /// Returns the timing and resource usage of each command in the pipeline, or `None` for the
+    /// ones which were not waited for yet.
+    pub fn usage(&self) -> Vec<Option<StageUsage>> {
+        self.usage.lock().unwrap().stages.clone()
+    }
+
+    /// Returns the current status of each command in the pipeline, without blocking.
+    pub fn statuses(&mut self) -> Vec<StageStatus> {
+        self.children.iter_mut().map(|x| x.try_wait()).collect()
+    }
+
     /// Forces the children processes to exit.
+    ///
+    /// For children spawned with the `new_pgroup` prefix, SIGKILL is sent to their whole
+    /// process group, so the processes they started are killed as well. Custom
+    /// commands running as threads are cancelled, see
+    /// [`CmdEnv::is_cancelled`](crate::CmdEnv::is_cancelled), and their pipes are closed.
     pub fn kill(&mut self) -> CmdResult {
         let mut ret = Ok(());
+        if self.pgid.is_some() {
+            ret = self.signal(Signal::Kill);
+        }
         while let Some(child_handle) = self.children.pop() {
             if let Err(e) = child_handle.kill() {
                 ret = Err(e);
             }
         }
+        self.usage.lock().unwrap().end_killed();
         ret
     }
 
+    /// Sends `signal` to the children processes.
+    ///
+    /// Children spawned with the `new_pgroup` prefix run in their own process group, and the
+    /// signal is delivered to the whole group, including grandchildren like the jobs of `make`
+    /// or the commands of a `bash -c` script.
+    pub fn signal(&self, signal: Signal) -> CmdResult {
+        let res = match self.pgid {
+            Some(pgid) => send_signal(-(pgid as i32), signal),
+            None => self
+                .pids()
+                .into_iter()
+                .map(|pid| send_signal(pid as i32, signal))
+                .fold(Ok(()), Result::and),
+        };
+        res.map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!(
+                    "Sending {signal} to [{}] failed: {e} at {}:{}",
+                    self.cmd, self.file, self.line
+                ),
+            )
+        })
+    }
+
+    /// Sends SIGINT to the children processes, like pressing Ctrl-C in a terminal.
+    pub fn interrupt(&self) -> CmdResult {
+        self.signal(Signal::Int)
+    }
+
+    /// Sends SIGTERM to the children processes and waits for them to exit, sending SIGKILL to
+    /// the ones still running after a grace period.
+    ///
+    /// Custom commands running as threads are cancelled, see
+    /// [`CmdEnv::is_cancelled`](crate::CmdEnv::is_cancelled). The ones still running after the
+    /// grace period are left running in the background, and reported with an error of kind
+    /// [`ErrorKind::TimedOut`].
+    ///
+    /// Being killed by a signal is expected, but the signals which could not be sent, and the
+    /// commands which could not be waited for or exited with another error are reported.
+    pub fn terminate(&mut self) -> CmdResult {
+        if self.children.is_empty() {
+            return Ok(());
+        }
+        self.cancel_tokens().iter().for_each(CancelToken::cancel);
+        self.signal(Signal::Term)?;
+        let deadline = Instant::now() + KILL_GRACE_PERIOD;
+        while self.statuses().contains(&StageStatus::Running) {
+            if Instant::now() >= deadline {
+                self.signal(Signal::Kill)?;
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let mut ret = Ok(());
+        while let Some(child) = self.children.pop() {
+            let res = if child.is_running_thread() {
+                // joining a custom command ignoring its cancellation could block forever
+                child.detach()
+            } else {
+                child.reap_terminated()
+            };
+            if let Err(e) = res {
+                ret = Err(e);
+            }
+        }
+        self.result = Some(copy_result(&ret));
+        ret
+    }
+
+    // Kills and reaps the stages of a pipeline which failed to spawn completely
+    pub(crate) fn abort(mut self) {
+        self.cancel_tokens().iter().for_each(CancelToken::cancel);
+        let _ = self.signal(Signal::Kill);
+        let _ = Self::wait_children(&mut self.children);
+    }
+
     /// Returns the OS-assigned process identifiers associated with these children processes
     pub fn pids(&self) -> Vec<u32> {
         self.children.iter().filter_map(|x| x.pid()).collect()
     }
+
+    fn cancel_tokens(&self) -> Vec<CancelToken> {
+        self.children
+            .iter()
+            .filter_map(|x| x.handle.cancel_token())
+            .collect()
+    }
+
+    /// Attaches the last `lines` lines of each command's stderr to its error, overriding
+    /// [`set_stderr_tail`](crate::set_stderr_tail) for these children. 0 disables it.
+    pub fn stderr_tail(mut self, lines: usize) -> Self {
+        for child in self.children.iter_mut() {
+            child.stderr_tail = lines;
+        }
+        self
+    }
+
+    /// Sends the stderr output of the children to `sink`, overriding
+    /// [`set_stderr_sink`](crate::set_stderr_sink) for these children.
+    pub fn stderr_sink(mut self, sink: StderrSink) -> Self {
+        for child in self.children.iter_mut() {
+            child.stderr_sink = sink.clone();
+        }
+        self
+    }
+
+    /// Prepends `prefix` to the forwarded stderr lines of the children, overriding
+    /// [`set_stderr_prefix`](crate::set_stderr_prefix) for these children.
+    pub fn stderr_prefix(mut self, prefix: StderrPrefix) -> Self {
+        for child in self.children.iter_mut() {
+            child.stderr_format.prefix = prefix.clone();
+        }
+        self
+    }
+
+    /// Starts the forwarded stderr lines of the children with a timestamp or not, overriding
+    /// [`set_stderr_timestamp`](crate::set_stderr_timestamp) for these children.
+    pub fn stderr_timestamp(mut self, enable: bool) -> Self {
+        for child in self.children.iter_mut() {
+            child.stderr_format.timestamp = enable;
+        }
+        self
+    }
+
+    /// Terminates the children when this handle is dropped before they were waited for, e.g.
+    /// on an early `?` return, like [`terminate`](Self::terminate) does. Errors are logged.
+    ///
+    /// By default dropped children keep running in the background.
+    pub fn kill_on_drop(mut self, kill_on_drop: bool) -> Self {
+        self.kill_on_drop = kill_on_drop;
+        self
+    }
+}
+
+impl Drop for CmdChildren {
+    fn drop(&mut self) {
+        if self.kill_on_drop && !self.children.is_empty() {
+            if let Err(e) = self.terminate() {
+                warn!("Terminating dropped children failed: {e}");
+            }
+        }
+    }
 }
 
 /// Representation of running or exited children processes with output, connected with pipes
@@ -72,41 +377,66 @@ impl CmdChildren {
 ///
 /// Calling [spawn_with_output!](../cmd_lib/macro.spawn_with_output.html) macro will return `Result<FunChildren>`
 pub struct FunChildren {
-    children: Vec<CmdChild>,
-    ignore_error: bool,
+    inner: CmdChildren,
 }
 
 impl FunChildren {
     /// Waits for the children processes to exit completely, returning the command result, stdout
     /// content string and stderr content string.
     pub fn wait_with_all(&mut self) -> (CmdResult, String, String) {
-        self.inner_wait_with_all(true)
+        let (res, stdout, stderr) = self.wait_with_all_raw();
+        (res, output_to_string(stdout), output_to_string(stderr))
+    }
+
+    /// Waits for the children processes to exit completely, returning the command result, and
+    /// stdout and stderr content as raw bytes.
+    pub fn wait_with_all_raw(&mut self) -> (CmdResult, Vec<u8>, Vec<u8>) {
+        let watchdog = self.inner.start_watchdog();
+        let (res, stdout, stderr) = self.inner_wait_with_all(true);
+        (Watchdog::check(watchdog, res), stdout, stderr)
     }
 
     /// Waits for the children processes to exit completely, returning the stdout output.
     pub fn wait_with_output(&mut self) -> FunResult {
+        let watchdog = self.inner.start_watchdog();
         let (res, stdout, _) = self.inner_wait_with_all(false);
-        if let Err(e) = res {
-            if !self.ignore_error {
+        if let Err(e) = Watchdog::check(watchdog, res) {
+            if !self.inner.ignore_error {
                 return Err(e);
             }
         }
-        Ok(stdout)
+        Ok(output_to_string(stdout))
+    }
+
+    /// Waits for the children processes to exit completely, returning the stdout output, and
+    /// terminating them if they are still running after `timeout`.
+    ///
+    /// See [`CmdChildren::wait_timeout`] for how the children are terminated.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> FunResult {
+        self.inner.timeout = Some(CmdTimeout::new(timeout));
+        self.wait_with_output()
     }
 
     /// Waits for the children processes to exit completely, and read all bytes from stdout into `buf`.
     pub fn wait_with_raw_output(&mut self, buf: &mut Vec<u8>) -> CmdResult {
+        let watchdog = self.inner.start_watchdog();
+        let res = self.inner_wait_with_raw_output(buf);
+        Watchdog::check(watchdog, res)
+    }
+
+    fn inner_wait_with_raw_output(&mut self, buf: &mut Vec<u8>) -> CmdResult {
         // wait for the last child result
-        let handle = self.children.pop().unwrap();
-        let wait_last = handle.wait_with_raw_output(self.ignore_error, buf);
+        let ignore_error = self.inner.ignore_error;
+        let handle = self.inner.children.pop().unwrap();
+        let wait_last = handle.wait_with_raw_output(ignore_error, buf);
         match wait_last {
             Err(e) => {
-                let _ = CmdChildren::wait_children(&mut self.children);
+                let _ = CmdChildren::wait_children(&mut self.inner.children);
                 Err(e)
             }
             Ok(_) => {
-                let ret = CmdChildren::wait_children(&mut self.children);
-                if self.ignore_error {
+                let ret = CmdChildren::wait_children(&mut self.inner.children);
+                if ignore_error {
                     Ok(())
                 } else {
                     ret
@@ -115,67 +445,576 @@ impl FunChildren {
         }
     }
 
+    /// Returns an iterator over the lines of stdout, yielding them as the children produce them.
+    ///
+    /// Once stdout is closed, the children are waited for, and an error is yielded as the last
+    /// item if they failed. Use [`FunLines::finish`] to stop early and get the result.
+    pub fn lines(&mut self) -> FunLines<'_> {
+        FunLines(StdoutStream::new(FunChildrenRef::Borrowed(self)))
+    }
+
+    /// Same as [`lines`](Self::lines), taking ownership of the children.
+    pub fn into_lines(self) -> FunLines<'static> {
+        FunLines(StdoutStream::new(FunChildrenRef::Owned(self)))
+    }
+
+    /// Returns an iterator over chunks of raw stdout bytes, yielding them as soon as they are
+    /// available.
+    ///
+    /// The children are waited for the same way as with [`lines`](Self::lines).
+    pub fn chunks(&mut self) -> FunChunks<'_> {
+        FunChunks(StdoutStream::new(FunChildrenRef::Borrowed(self)))
+    }
+
+    /// Same as [`chunks`](Self::chunks), taking ownership of the children.
+    pub fn into_chunks(self) -> FunChunks<'static> {
+        FunChunks(StdoutStream::new(FunChildrenRef::Owned(self)))
+    }
+
     /// Waits for the children processes to exit completely, pipe content will be processed by
     /// provided function.
     pub fn wait_with_pipe(&mut self, f: &mut dyn FnMut(Box<dyn Read>)) -> CmdResult {
-        let child = self.children.pop().unwrap();
-        let stderr_thread =
-            StderrThread::new(&child.cmd, &child.file, child.line, child.stderr, false);
-        match child.handle {
-            CmdChildHandle::Proc(mut proc) => {
-                if let Some(stdout) = child.stdout {
-                    f(Box::new(stdout));
-                    let _ = proc.kill();
-                }
+        let watchdog = self.inner.start_watchdog();
+        let res = self.inner_wait_with_pipe(f);
+        Watchdog::check(watchdog, res)
+    }
+
+    fn inner_wait_with_pipe(&mut self, f: &mut dyn FnMut(Box<dyn Read>)) -> CmdResult {
+        let mut child = self.inner.children.pop().unwrap();
+        let stderr_thread = child.stderr_thread(false);
+        if let Some(stdout) = child.stdout.take() {
+            f(Box::new(stdout));
+            if let CmdChildHandle::Proc(proc) = &mut child.handle {
+                let _ = proc.kill();
+                // reap it, recording its status
+                let _ = child.finish();
             }
-            CmdChildHandle::Thread(_) => {
-                if let Some(stdout) = child.stdout {
-                    f(Box::new(stdout));
-                }
+        }
+        drop(stderr_thread);
+        let res = CmdChildren::wait_children(&mut self.inner.children);
+        self.inner.usage.lock().unwrap().end();
+        res
+    }
+
+    /// Returns the timing and resource usage of each command in the pipeline, e.g. after
+    /// [`wait_with_all`](Self::wait_with_all).
+    ///
+    /// See [`CmdChildren::usage`].
+    pub fn usage(&self) -> Vec<Option<StageUsage>> {
+        self.inner.usage()
+    }
+
+    /// Forces the children processes to exit.
+    ///
+    /// See [`CmdChildren::kill`] for which processes are killed.
+    pub fn kill(&mut self) -> CmdResult {
+        self.inner.kill()
+    }
+
+    /// Sends `signal` to the children processes.
+    ///
+    /// See [`CmdChildren::signal`] for which processes receive it.
+    pub fn signal(&self, signal: Signal) -> CmdResult {
+        self.inner.signal(signal)
+    }
+
+    /// Sends SIGINT to the children processes, like pressing Ctrl-C in a terminal.
+    pub fn interrupt(&self) -> CmdResult {
+        self.inner.interrupt()
+    }
+
+    /// Sends SIGTERM to the children processes and waits for them to exit, sending SIGKILL to
+    /// the ones still running after a grace period.
+    pub fn terminate(&mut self) -> CmdResult {
+        self.inner.terminate()
+    }
+
+    /// Returns the OS-assigned process identifiers associated with these children processes.
+    pub fn pids(&self) -> Vec<u32> {
+        self.inner.pids()
+    }
+
+    /// Attaches the last `lines` lines of each command's stderr to its error, overriding
+    /// [`set_stderr_tail`](crate::set_stderr_tail) for these children. 0 disables it.
+    pub fn stderr_tail(self, lines: usize) -> Self {
+        self.inner.stderr_tail(lines).into_fun_children()
+    }
+
+    /// Sends the stderr output of the children to `sink`, overriding
+    /// [`set_stderr_sink`](crate::set_stderr_sink) for these children.
+    pub fn stderr_sink(self, sink: StderrSink) -> Self {
+        self.inner.stderr_sink(sink).into_fun_children()
+    }
+
+    /// Prepends `prefix` to the forwarded stderr lines of the children, overriding
+    /// [`set_stderr_prefix`](crate::set_stderr_prefix) for these children.
+    pub fn stderr_prefix(self, prefix: StderrPrefix) -> Self {
+        self.inner.stderr_prefix(prefix).into_fun_children()
+    }
+
+    /// Starts the forwarded stderr lines of the children with a timestamp or not, overriding
+    /// [`set_stderr_timestamp`](crate::set_stderr_timestamp) for these children.
+    pub fn stderr_timestamp(self, enable: bool) -> Self {
+        self.inner.stderr_timestamp(enable).into_fun_children()
+    }
+
+    /// Copies the stdout output to `tee` while it is being read, overriding
+    /// [`set_tee`](crate::set_tee) for these children.
+    ///
+    /// Only the waits collecting the output, like [`wait_with_output`](Self::wait_with_output),
+    /// copy it.
+    pub fn tee(mut self, tee: Tee) -> Self {
+        if let Some(last) = self.inner.children.last_mut() {
+            last.tee = tee;
+        }
+        self
+    }
+
+    /// Terminates the children when this handle is dropped before they were waited for.
+    ///
+    /// See [`CmdChildren::kill_on_drop`].
+    pub fn kill_on_drop(self, kill_on_drop: bool) -> Self {
+        self.inner.kill_on_drop(kill_on_drop).into_fun_children()
+    }
+
+    /// Waits for the children processes to exit completely, returning the command result and
+    /// the stdout and stderr output of the last command, interleaved in the order it was read.
+    ///
+    /// Each chunk is tagged with the stream it came from, and with the time it was read if
+    /// `timestamps` is true.
+    pub fn wait_with_transcript(&mut self, timestamps: bool) -> (CmdResult, Vec<OutputChunk>) {
+        let watchdog = self.inner.start_watchdog();
+        let handle = self.inner.children.pop().unwrap();
+        let mut transcript = vec![];
+        let res = handle.wait_with_transcript(timestamps, &mut transcript);
+        let _ = CmdChildren::wait_children(&mut self.inner.children);
+        (Watchdog::check(watchdog, res), transcript)
+    }
+
+    fn inner_wait_with_all(&mut self, capture_stderr: bool) -> (CmdResult, Vec<u8>, Vec<u8>) {
+        // wait for the last child result
+        let handle = self.inner.children.pop().unwrap();
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let res = handle.wait_with_all(capture_stderr, &mut stdout, &mut stderr);
+        let _ = CmdChildren::wait_children(&mut self.inner.children);
+        (res, stdout, stderr)
+    }
+}
+
+/// Iterator over the lines of stdout of [`FunChildren`], see [`FunChildren::lines`].
+pub struct FunLines<'a>(StdoutStream<'a>);
+
+impl FunLines<'_> {
+    /// Stops reading, discarding the rest of stdout, and waits for the children to exit.
+    ///
+    /// Returns the same result as [`FunChildren::wait_with_output`] would, or `Ok` if it was
+    /// already yielded by the iterator.
+    pub fn finish(mut self) -> CmdResult {
+        self.0.finish()
+    }
+}
+
+impl Iterator for FunLines<'_> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_with(|stdout| {
+            let mut line = String::new();
+            if stdout.read_line(&mut line)? == 0 {
+                return Ok(None);
             }
-            CmdChildHandle::SyncFn => {
-                if let Some(stdout) = child.stdout {
-                    f(Box::new(stdout));
+            if line.ends_with('\n') {
+                line.pop();
+                if line.ends_with('\r') {
+                    line.pop();
                 }
             }
+            Ok(Some(line))
+        })
+    }
+}
+
+/// Iterator over chunks of stdout of [`FunChildren`], see [`FunChildren::chunks`].
+pub struct FunChunks<'a>(StdoutStream<'a>);
+
+impl FunChunks<'_> {
+    /// Stops reading, discarding the rest of stdout, and waits for the children to exit.
+    ///
+    /// See [`FunLines::finish`].
+    pub fn finish(mut self) -> CmdResult {
+        self.0.finish()
+    }
+}
+
+impl Iterator for FunChunks<'_> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_with(|stdout| {
+            let chunk = stdout.fill_buf()?.to_vec();
+            stdout.consume(chunk.len());
+            Ok(if chunk.is_empty() { None } else { Some(chunk) })
+        })
+    }
+}
+
+enum FunChildrenRef<'a> {
+    Owned(FunChildren),
+    Borrowed(&'a mut FunChildren),
+}
+
+impl FunChildrenRef<'_> {
+    fn get(&mut self) -> &mut FunChildren {
+        match self {
+            FunChildrenRef::Owned(children) => children,
+            FunChildrenRef::Borrowed(children) => children,
+        }
+    }
+}
+
+// Reads the last command's stdout incrementally, waiting for the children at the end of it
+struct StdoutStream<'a> {
+    children: FunChildrenRef<'a>,
+    stdout: Option<BufReader<PipeReader>>,
+    watchdog: Option<Watchdog>,
+    done: bool,
+}
+
+impl<'a> StdoutStream<'a> {
+    fn new(mut children: FunChildrenRef<'a>) -> Self {
+        let inner = &mut children.get().inner;
+        let watchdog = inner.start_watchdog();
+        inner.children.iter_mut().for_each(CmdChild::forward_stderr);
+        let stdout = inner
+            .children
+            .last_mut()
+            .and_then(|child| child.stdout.take())
+            .map(BufReader::new);
+        Self {
+            children,
+            stdout,
+            watchdog,
+            done: false,
+        }
+    }
+
+    fn next_with<T>(
+        &mut self,
+        read: impl FnOnce(&mut BufReader<PipeReader>) -> Result<Option<T>>,
+    ) -> Option<Result<T>> {
+        if self.done {
+            return None;
+        }
+        if let Some(stdout) = self.stdout.as_mut() {
+            match read(stdout) {
+                Ok(Some(item)) => return Some(Ok(item)),
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.finish().err().map(Err)
+    }
+
+    fn finish(&mut self) -> CmdResult {
+        if self.done {
+            return Ok(());
+        }
+        self.done = true;
+        if let Some(mut stdout) = self.stdout.take() {
+            let _ = std::io::copy(&mut stdout, &mut std::io::sink());
+        }
+        let inner = &mut self.children.get().inner;
+        let ignore_error = inner.ignore_error;
+        let res = if inner.children.is_empty() {
+            Ok(())
+        } else {
+            inner.inner_wait()
         };
-        drop(stderr_thread);
-        CmdChildren::wait_children(&mut self.children)
+        match Watchdog::check(self.watchdog.take(), res) {
+            Err(_) if ignore_error => Ok(()),
+            res => res,
+        }
+    }
+}
+
+/// Signals which can be sent to children processes with [`CmdChildren::signal`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// SIGHUP, hangup detected on controlling terminal.
+    Hup,
+    /// SIGINT, interrupt from keyboard.
+    Int,
+    /// SIGQUIT, quit from keyboard.
+    Quit,
+    /// SIGKILL, kill signal which can't be caught or ignored.
+    Kill,
+    /// SIGUSR1, user-defined signal 1.
+    Usr1,
+    /// SIGUSR2, user-defined signal 2.
+    Usr2,
+    /// SIGTERM, termination signal.
+    Term,
+    /// SIGCONT, continue if stopped.
+    Cont,
+    /// SIGSTOP, stop process which can't be caught or ignored.
+    Stop,
+}
+
+impl Signal {
+    fn as_raw(self) -> libc::c_int {
+        match self {
+            Signal::Hup => libc::SIGHUP,
+            Signal::Int => libc::SIGINT,
+            Signal::Quit => libc::SIGQUIT,
+            Signal::Kill => libc::SIGKILL,
+            Signal::Usr1 => libc::SIGUSR1,
+            Signal::Usr2 => libc::SIGUSR2,
+            Signal::Term => libc::SIGTERM,
+            Signal::Cont => libc::SIGCONT,
+            Signal::Stop => libc::SIGSTOP,
+        }
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SIG{}", format!("{self:?}").to_uppercase())
+    }
+}
+
+// Errors are not `Clone`, so cached results are copied with the same kind and message
+pub(crate) fn copy_result(res: &CmdResult) -> CmdResult {
+    match res {
+        Ok(()) => Ok(()),
+        Err(e) => Err(Error::new(e.kind(), e.to_string())),
+    }
+}
+
+pub(crate) fn timed_out_error(cmd: &str, duration: Duration, file: &str, line: u32) -> Error {
+    Error::new(
+        ErrorKind::TimedOut,
+        format!("Running [{cmd}] timed out after {duration:?} at {file}:{line}"),
+    )
+}
+
+// Sends the signal to a process, or to a process group with a negative pid
+pub(crate) fn send_signal(pid: i32, signal: Signal) -> Result<()> {
+    // SAFETY: kill has no memory safety requirements
+    if unsafe { libc::kill(pid as libc::pid_t, signal.as_raw()) } != 0 {
+        let e = Error::last_os_error();
+        // already exited and reaped
+        if e.raw_os_error() != Some(libc::ESRCH) {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Status of a single command in a pipeline, as returned by [`CmdChildren::statuses`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StageStatus {
+    /// The command is still running.
+    Running,
+    /// The command exited with this status code. Builtin and custom commands which
+    /// succeeded report a code of 0.
+    Exited(i32),
+    /// The process was terminated by this signal.
+    Signaled(i32),
+    /// The builtin or custom command failed, or the process could not be waited for.
+    Error(String),
+}
+
+impl StageStatus {
+    /// Returns true if the command is finished successfully.
+    pub fn success(&self) -> bool {
+        *self == StageStatus::Exited(0)
+    }
+
+    /// Returns the status code if the command has exited.
+    pub fn code(&self) -> Option<i32> {
+        match self {
+            StageStatus::Exited(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Returns the signal which terminated the process, if any.
+    pub fn signal(&self) -> Option<i32> {
+        match self {
+            StageStatus::Signaled(signal) => Some(*signal),
+            _ => None,
+        }
+    }
+
+    fn from_exit_status(status: ExitStatus) -> Self {
+        match (status.code(), status.signal()) {
+            (Some(code), _) => StageStatus::Exited(code),
+            (None, Some(signal)) => StageStatus::Signaled(signal),
+            (None, None) => StageStatus::Error(format!("unknown exit status: {status}")),
+        }
+    }
+}
+
+/// Outcome of a single command in a pipeline, as returned by [`CmdChildren::wait_all`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageOutcome {
+    /// The command string of this stage.
+    pub cmd: String,
+    /// How the command exited, which is never [`StageStatus::Running`].
+    pub status: StageStatus,
+    /// How long the command ran and the resources it used.
+    pub usage: StageUsage,
+}
+
+struct CmdTimeout {
+    duration: Duration,
+    deadline: Instant,
+}
+
+impl CmdTimeout {
+    fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            deadline: Instant::now() + duration,
+        }
+    }
+}
+
+// Terminates the children processes from a separate thread once the deadline is reached, so
+// all the blocking wait methods can be bounded in time.
+struct Watchdog {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    timed_out: Arc<AtomicBool>,
+    duration: Duration,
+    cmd: String,
+    file: String,
+    line: u32,
+}
+
+impl Watchdog {
+    fn start(
+        timeout: &CmdTimeout,
+        pgid: Option<u32>,
+        pids: Vec<u32>,
+        cancels: Vec<CancelToken>,
+        cmd: &str,
+        file: &str,
+        line: u32,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let deadline = timeout.deadline;
+        let thread = {
+            let timed_out = timed_out.clone();
+            std::thread::spawn(move || {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if stopped.recv_timeout(remaining) != Err(RecvTimeoutError::Timeout) {
+                    return;
+                }
+                // Signals the stages which are still running, returning whether there were any
+                let signal_running = |signal| {
+                    let _reaping = REAPING.lock().unwrap();
+                    let unreaped: Vec<_> = pids
+                        .iter()
+                        .filter_map(|pid| peek_proc(*pid).map(|running| (*pid, running)))
+                        .collect();
+                    let running = unreaped.iter().any(|(_, running)| *running)
+                        || cancels.iter().any(|cancel| !cancel.is_finished());
+                    if !running {
+                        return false;
+                    }
+                    cancels.iter().for_each(CancelToken::cancel);
+                    match pgid {
+                        // the group can't be reused while one of its processes is not reaped
+                        Some(pgid) if !unreaped.is_empty() => {
+                            let _ = send_signal(-(pgid as i32), signal);
+                        }
+                        Some(_) => {}
+                        None => unreaped.iter().for_each(|(pid, _)| {
+                            let _ = send_signal(*pid as i32, signal);
+                        }),
+                    }
+                    true
+                };
+                if !signal_running(Signal::Term) {
+                    return;
+                }
+                timed_out.store(true, Ordering::SeqCst);
+                if stopped.recv_timeout(KILL_GRACE_PERIOD) != Err(RecvTimeoutError::Timeout) {
+                    return;
+                }
+                signal_running(Signal::Kill);
+            })
+        };
+        Self {
+            stop: Some(stop),
+            thread: Some(thread),
+            timed_out,
+            duration: timeout.duration,
+            cmd: cmd.into(),
+            file: file.into(),
+            line,
+        }
+    }
+
+    fn check(watchdog: Option<Self>, res: CmdResult) -> CmdResult {
+        match watchdog {
+            Some(mut watchdog) => {
+                watchdog.stop();
+                if watchdog.timed_out.load(Ordering::SeqCst) {
+                    return Err(timed_out_error(
+                        &watchdog.cmd,
+                        watchdog.duration,
+                        &watchdog.file,
+                        watchdog.line,
+                    ));
+                }
+                res
+            }
+            None => res,
+        }
     }
 
-    /// Returns the OS-assigned process identifiers associated with these children processes.
-    pub fn pids(&self) -> Vec<u32> {
-        self.children.iter().filter_map(|x| x.pid()).collect()
+    fn stop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
     }
+}
 
-    fn inner_wait_with_all(&mut self, capture_stderr: bool) -> (CmdResult, String, String) {
-        // wait for the last child result
-        let handle = self.children.pop().unwrap();
-        let mut stdout_buf = Vec::new();
-        let mut stderr = String::new();
-        let res = handle.wait_with_all(capture_stderr, &mut stdout_buf, &mut stderr);
-        let _ = CmdChildren::wait_children(&mut self.children);
-        let mut stdout: String = String::from_utf8_lossy(&stdout_buf).into();
-        if stdout.ends_with('\n') {
-            stdout.pop();
-        }
-        (res, stdout, stderr)
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop();
     }
 }
 
 pub(crate) struct CmdChild {
     handle: CmdChildHandle,
     cmd: String,
+    name: String,
     file: String,
     line: u32,
     stdout: Option<PipeReader>,
     stderr: Option<PipeReader>,
+    stderr_thread: Option<StderrThread>,
+    stderr_tail: usize,
+    stderr_sink: StderrSink,
+    stderr_format: LineFormat,
+    tee: Tee,
+    pipefail: bool,
+    started: Instant,
+    usage: Arc<Mutex<UsageRecorder>>,
+    stage: usize,
 }
 
 impl CmdChild {
     pub(crate) fn new(
         handle: CmdChildHandle,
         cmd: String,
+        name: String,
         file: String,
         line: u32,
         stdout: Option<PipeReader>,
@@ -186,25 +1025,58 @@ impl CmdChild {
             line,
             handle,
             cmd,
+            name,
             stdout,
             stderr,
+            stderr_thread: None,
+            stderr_tail: process::stderr_tail_lines(),
+            stderr_sink: process::stderr_sink(),
+            stderr_format: LineFormat::default(),
+            tee: process::tee(),
+            pipefail: process::pipefail_enabled(),
+            started: Instant::now(),
+            usage: Arc::default(),
+            stage: 0,
+        }
+    }
+
+    // Waits for the command to finish, recording how long it ran and the resources it used
+    fn finish(&mut self) -> (Result<Option<ExitStatus>>, StageUsage) {
+        let handle = std::mem::replace(&mut self.handle, CmdChildHandle::SyncFn);
+        let (res, rusage) = handle.finish(&self.cmd, &self.file, self.line);
+        let mut recorder = self.usage.lock().unwrap();
+        let exited = recorder.exited.get(self.stage).copied().flatten();
+        let usage = StageUsage {
+            wall_time: exited.unwrap_or_else(Instant::now) - self.started,
+            rusage,
+        };
+        recorder.record_status(self.stage, &res);
+        recorder.record(self.stage, usage.clone());
+        (res, usage)
+    }
+
+    fn wait_handle(&mut self) -> CmdResult {
+        match self.finish().0? {
+            Some(status) if !status.success() => Err(CmdChildHandle::status_to_io_error(
+                status, &self.cmd, &self.file, self.line,
+            )),
+            _ => Ok(()),
         }
     }
 
     fn wait(mut self, is_last: bool) -> CmdResult {
-        let _stderr_thread =
-            StderrThread::new(&self.cmd, &self.file, self.line, self.stderr.take(), false);
-        let res = self.handle.wait(&self.cmd, &self.file, self.line);
+        let mut stderr_thread = self.stderr_thread(false);
+        let res = self.wait_handle();
         if let Err(e) = res {
-            if is_last || process::pipefail_enabled() {
-                return Err(e);
+            if is_last || self.pipefail {
+                return Err(stderr_thread.join().attach_tail(e));
             }
         }
         Ok(())
     }
 
     fn wait_with_raw_output(self, ignore_error: bool, stdout_buf: &mut Vec<u8>) -> CmdResult {
-        let mut _stderr = String::new();
+        let mut _stderr = Vec::new();
         let res = self.wait_with_all(false, stdout_buf, &mut _stderr);
         if ignore_error {
             return Ok(());
@@ -216,91 +1088,222 @@ impl CmdChild {
         mut self,
         capture_stderr: bool,
         stdout_buf: &mut Vec<u8>,
-        stderr_buf: &mut String,
+        stderr_buf: &mut Vec<u8>,
     ) -> CmdResult {
-        let mut stderr_thread = StderrThread::new(
-            &self.cmd,
-            &self.file,
-            self.line,
-            self.stderr.take(),
-            capture_stderr,
-        );
+        let mut stderr_thread = self.stderr_thread(capture_stderr);
         let mut stdout_res = Ok(());
         if let Some(mut stdout) = self.stdout.take() {
-            if let Err(e) = stdout.read_to_end(stdout_buf) {
-                stdout_res = Err(e)
-            }
+            stdout_res = self.tee.read_to_end(&mut stdout, stdout_buf);
         }
-        *stderr_buf = stderr_thread.join();
-        let wait_res = self.handle.wait(&self.cmd, &self.file, self.line);
+        let stderr_output = stderr_thread.join();
+        let wait_res = self.wait_handle().map_err(|e| stderr_output.attach_tail(e));
+        *stderr_buf = stderr_output.captured;
         wait_res.and(stdout_res)
     }
 
+    fn wait_with_transcript(
+        mut self,
+        timestamps: bool,
+        transcript: &mut Vec<OutputChunk>,
+    ) -> CmdResult {
+        let mut pipes = vec![];
+        if let Some(stdout) = self.stdout.take() {
+            pipes.push((OutputStream::Stdout, stdout));
+        }
+        if let Some(stderr) = self.stderr.take() {
+            pipes.push((OutputStream::Stderr, stderr));
+        }
+        // stderr may already be forwarded by a thread, while being polled
+        let stderr_thread = self.stderr_thread.take();
+        let read_res = read_transcript(pipes, timestamps, transcript);
+        let wait_res = self.wait_handle();
+        if let Some(mut stderr_thread) = stderr_thread {
+            stderr_thread.join();
+        }
+        wait_res.and(read_res)
+    }
+
+    fn stderr_thread(&mut self, capture: bool) -> StderrThread {
+        if let Some(stderr_thread) = self.stderr_thread.take() {
+            // already forwarding stderr while being polled
+            return stderr_thread;
+        }
+        let sink = if capture {
+            None
+        } else {
+            Some(self.stderr_sink.clone())
+        };
+        StderrThread::new(self, sink)
+    }
+
+    fn wait_outcome(mut self) -> StageOutcome {
+        let mut stderr_thread = self.stderr_thread(false);
+        let (res, usage) = self.finish();
+        stderr_thread.join();
+        let status = match res {
+            Ok(Some(status)) => StageStatus::from_exit_status(status),
+            Ok(None) => StageStatus::Exited(0),
+            Err(e) => StageStatus::Error(e.to_string()),
+        };
+        StageOutcome {
+            cmd: self.cmd,
+            status,
+            usage,
+        }
+    }
+
+    // Reaps a terminated command, which is expected to be killed by a signal, to exit with the
+    // status of a shell killed by one, or to fail once cancelled
+    fn reap_terminated(mut self) -> CmdResult {
+        let mut stderr_thread = self.stderr_thread(false);
+        let is_thread = matches!(
+            self.handle,
+            CmdChildHandle::Thread(..) | CmdChildHandle::Joined(_)
+        );
+        let (res, _) = self.finish();
+        stderr_thread.join();
+        let signaled = |code| {
+            [Signal::Int, Signal::Term, Signal::Kill]
+                .iter()
+                .any(|signal| code == 128 + signal.as_raw())
+        };
+        match res {
+            Ok(Some(status)) => match status.code() {
+                Some(code) if code != 0 && !signaled(code) => Err(
+                    CmdChildHandle::status_to_io_error(status, &self.cmd, &self.file, self.line),
+                ),
+                _ => Ok(()),
+            },
+            Ok(None) => Ok(()),
+            Err(_) if is_thread => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn is_running_thread(&self) -> bool {
+        matches!(&self.handle, CmdChildHandle::Thread(thread, _) if !thread.is_finished())
+    }
+
+    // Leaves the thread of a custom command running in the background, along with the
+    // forwarding of its stderr
+    fn detach(mut self) -> CmdResult {
+        if let Some(mut stderr_thread) = self.stderr_thread.take() {
+            stderr_thread.thread.take();
+        }
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            format!(
+                "Running [{}] still running after being cancelled, left in the background at {}:{}",
+                self.cmd, self.file, self.line
+            ),
+        ))
+    }
+
+    fn try_wait(&mut self) -> StageStatus {
+        self.forward_stderr();
+        self.handle.try_wait(&self.cmd, &self.file, self.line)
+    }
+
+    fn forward_stderr(&mut self) {
+        if self.stderr_thread.is_none() {
+            // keep draining stderr, or the process could block on a full pipe
+            self.stderr_thread = Some(self.stderr_thread(false));
+        }
+    }
+
     fn kill(self) -> CmdResult {
         self.handle.kill(&self.cmd, &self.file, self.line)
     }
 
-    fn pid(&self) -> Option<u32> {
+    pub(crate) fn pid(&self) -> Option<u32> {
         self.handle.pid()
     }
 }
 
 pub(crate) enum CmdChildHandle {
     Proc(Child),
-    Thread(JoinHandle<CmdResult>),
+    Thread(JoinHandle<CmdResult>, CancelToken),
+    // thread which was already joined while being polled
+    Joined(CmdResult),
+    // process which was already reaped while being polled
+    Reaped(ExitStatus, Option<ResourceUsage>),
     SyncFn,
 }
 
 impl CmdChildHandle {
-    fn wait(self, cmd: &str, file: &str, line: u32) -> CmdResult {
+    fn try_wait(&mut self, cmd: &str, file: &str, line: u32) -> StageStatus {
         match self {
-            CmdChildHandle::Proc(mut proc) => {
-                let status = proc.wait();
-                match status {
-                    Err(e) => return Err(process::new_cmd_io_error(&e, cmd, file, line)),
-                    Ok(status) => {
-                        if !status.success() {
-                            return Err(Self::status_to_io_error(status, cmd, file, line));
-                        }
-                    }
+            CmdChildHandle::Proc(proc) => match wait_proc(proc, false) {
+                Ok(Some((status, rusage))) => {
+                    *self = CmdChildHandle::Reaped(status, rusage);
+                    StageStatus::from_exit_status(status)
                 }
-            }
-            CmdChildHandle::Thread(thread) => {
-                let status = thread.join();
-                match status {
-                    Ok(result) => {
-                        if let Err(e) = result {
-                            return Err(process::new_cmd_io_error(&e, cmd, file, line));
-                        }
-                    }
-                    Err(e) => {
-                        return Err(Error::new(
-                            ErrorKind::Other,
-                            format!(
-                                "Running [{cmd}] thread joined with error: {e:?} at {file}:{line}"
-                            ),
-                        ))
-                    }
+                Ok(None) => StageStatus::Running,
+                Err(e) => StageStatus::Error(e.to_string()),
+            },
+            CmdChildHandle::Thread(thread, _) => {
+                if !thread.is_finished() {
+                    return StageStatus::Running;
                 }
+                if let CmdChildHandle::Thread(thread, _) =
+                    std::mem::replace(self, CmdChildHandle::SyncFn)
+                {
+                    *self = CmdChildHandle::Joined(Self::join(thread, cmd, file, line));
+                }
+                self.try_wait(cmd, file, line)
             }
-            CmdChildHandle::SyncFn => {}
+            CmdChildHandle::Joined(Ok(())) | CmdChildHandle::SyncFn => StageStatus::Exited(0),
+            CmdChildHandle::Joined(Err(e)) => StageStatus::Error(e.to_string()),
+            CmdChildHandle::Reaped(status, _) => StageStatus::from_exit_status(*status),
+        }
+    }
+
+    // Waits for the command to finish, returning the exit status of processes, and their
+    // resource usage where it is available
+    fn finish(
+        self,
+        cmd: &str,
+        file: &str,
+        line: u32,
+    ) -> (Result<Option<ExitStatus>>, Option<ResourceUsage>) {
+        match self {
+            CmdChildHandle::Proc(mut proc) => match wait_proc(&mut proc, true) {
+                Ok(Some((status, rusage))) => (Ok(Some(status)), rusage),
+                Ok(None) => unreachable!("blocking wait returned no status"),
+                Err(e) => (Err(process::new_cmd_io_error(&e, cmd, file, line)), None),
+            },
+            CmdChildHandle::Thread(thread, _) => {
+                (Self::join(thread, cmd, file, line).map(|_| None), None)
+            }
+            CmdChildHandle::Joined(res) => (res.map(|_| None), None),
+            CmdChildHandle::Reaped(status, rusage) => (Ok(Some(status)), rusage),
+            CmdChildHandle::SyncFn => (Ok(None), None),
         }
-        Ok(())
     }
 
-    fn status_to_io_error(status: ExitStatus, cmd: &str, file: &str, line: u32) -> Error {
+    fn join(thread: JoinHandle<CmdResult>, cmd: &str, file: &str, line: u32) -> CmdResult {
+        match thread.join() {
+            Ok(result) => result.map_err(|e| process::new_cmd_io_error(&e, cmd, file, line)),
+            Err(e) => Err(Error::other(format!(
+                "Running [{cmd}] thread joined with error: {e:?} at {file}:{line}"
+            ))),
+        }
+    }
+
+    pub(crate) fn status_to_io_error(
+        status: ExitStatus,
+        cmd: &str,
+        file: &str,
+        line: u32,
+    ) -> Error {
         if let Some(code) = status.code() {
-            Error::new(
-                ErrorKind::Other,
-                format!("Running [{cmd}] exited with error; status code: {code} at {file}:{line}"),
-            )
+            Error::other(format!(
+                "Running [{cmd}] exited with error; status code: {code} at {file}:{line}"
+            ))
         } else {
-            Error::new(
-                ErrorKind::Other,
-                format!(
-                    "Running [{cmd}] exited with error; terminated by {status} at {file}:{line}"
-                ),
-            )
+            Error::other(format!(
+                "Running [{cmd}] exited with error; terminated by {status} at {file}:{line}"
+            ))
         }
     }
 
@@ -312,11 +1315,22 @@ impl CmdChildHandle {
                     format!("Killing process [{cmd}] failed with error: {e} at {file}:{line}"),
                 )
             }),
-            CmdChildHandle::Thread(_thread) => Err(Error::new(
-                ErrorKind::Other,
-                format!("Killing thread [{cmd}] failed: not supported at {file}:{line}"),
-            )),
-            CmdChildHandle::SyncFn => Ok(()),
+            CmdChildHandle::Thread(_thread, cancel) => {
+                // the thread is left to notice the cancellation, and the pipes it uses are
+                // closed as its children handle is dropped
+                cancel.cancel();
+                Ok(())
+            }
+            CmdChildHandle::Joined(_) | CmdChildHandle::Reaped(..) | CmdChildHandle::SyncFn => {
+                Ok(())
+            }
+        }
+    }
+
+    fn cancel_token(&self) -> Option<CancelToken> {
+        match self {
+            CmdChildHandle::Thread(_, cancel) => Some(cancel.clone()),
+            _ => None,
         }
     }
 
@@ -328,32 +1342,343 @@ impl CmdChildHandle {
     }
 }
 
+// Waits for a process with `wait4` on Linux, to get its resource usage as well. Without
+// `block`, `None` is returned while it is still running.
+#[cfg(target_os = "linux")]
+fn wait_proc(proc: &mut Child, block: bool) -> Result<Option<(ExitStatus, Option<ResourceUsage>)>> {
+    if block {
+        // wait for the exit without reaping, so that the pid can't be reused while a timeout
+        // could still signal it
+        loop {
+            // SAFETY: siginfo_t is plain old data, which waitid fills in
+            let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+            let options = libc::WEXITED | libc::WNOWAIT;
+            // SAFETY: the pointer is valid for the duration of the call
+            if unsafe { libc::waitid(libc::P_PID, proc.id(), &mut info, options) } == 0 {
+                break;
+            }
+            let e = Error::last_os_error();
+            if e.kind() != ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+    let _reaping = REAPING.lock().unwrap();
+    let options = if block { 0 } else { libc::WNOHANG };
+    let mut status = 0;
+    // SAFETY: rusage is plain old data, which is all zeros before wait4 fills it in
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: the pointers are valid for the duration of the call
+        let pid =
+            unsafe { libc::wait4(proc.id() as libc::pid_t, &mut status, options, &mut rusage) };
+        match pid {
+            0 => return Ok(None),
+            pid if pid > 0 => {
+                let status = ExitStatus::from_raw(status);
+                return Ok(Some((status, Some(ResourceUsage::from_rusage(&rusage)))));
+            }
+            _ => {
+                let e = Error::last_os_error();
+                if e.kind() != ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+// Checks a child process without reaping it, returning `None` once it was reaped, or whether
+// it is still running
+#[cfg(target_os = "linux")]
+fn peek_proc(pid: u32) -> Option<bool> {
+    // SAFETY: siginfo_t is plain old data, which waitid fills in
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let options = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    // SAFETY: the pointer is valid for the duration of the call
+    if unsafe { libc::waitid(libc::P_PID, pid, &mut info, options) } != 0 {
+        return None;
+    }
+    // SAFETY: waitid succeeded, so si_pid is set, to 0 if the process has not exited
+    Some(unsafe { info.si_pid() } == 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peek_proc(_pid: u32) -> Option<bool> {
+    Some(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn wait_proc(proc: &mut Child, block: bool) -> Result<Option<(ExitStatus, Option<ResourceUsage>)>> {
+    let status = if block {
+        Some(proc.wait()?)
+    } else {
+        proc.try_wait()?
+    };
+    Ok(status.map(|status| (status, None)))
+}
+
+/// Resource usage of a finished process, as reported by `wait4`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// CPU time spent in user mode.
+    pub user_time: Duration,
+    /// CPU time spent in kernel mode.
+    pub sys_time: Duration,
+    /// Maximum resident set size, in kilobytes.
+    pub max_rss: u64,
+    /// Page faults serviced without any I/O.
+    pub minor_faults: u64,
+    /// Page faults which required I/O.
+    pub major_faults: u64,
+}
+
+impl ResourceUsage {
+    #[cfg(target_os = "linux")]
+    fn from_rusage(rusage: &libc::rusage) -> Self {
+        let duration =
+            |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+        Self {
+            user_time: duration(rusage.ru_utime),
+            sys_time: duration(rusage.ru_stime),
+            max_rss: rusage.ru_maxrss as u64,
+            minor_faults: rusage.ru_minflt as u64,
+            major_faults: rusage.ru_majflt as u64,
+        }
+    }
+}
+
+/// Timing and resource usage of a single command in a pipeline, see [`CmdChildren::usage`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StageUsage {
+    /// Time from spawning the command until it exited, within a few milliseconds for the
+    /// commands before the last one of a pipeline. On platforms other than Linux, these report
+    /// the time until they were reaped instead, after the last one finished.
+    pub wall_time: Duration,
+    /// Resource usage of the process, only collected on Linux. Builtin and custom commands
+    /// have none.
+    pub rusage: Option<ResourceUsage>,
+}
+
+#[derive(Default)]
+pub(crate) struct UsageRecorder {
+    cmd: String,
+    report: bool,
+    stages: Vec<Option<StageUsage>>,
+    statuses: Vec<String>,
+    xtrace: Xtrace,
+    span: CmdSpan,
+    started: Option<Instant>,
+    // when each stage was seen exiting, before being reaped
+    exited: Vec<Option<Instant>>,
+    ended: bool,
+}
+
+impl UsageRecorder {
+    fn new(cmd: String, len: usize) -> Self {
+        let mut recorder = Self::default();
+        recorder.cmd = cmd;
+        recorder.stages = vec![None; len];
+        recorder.statuses = vec![String::new(); len];
+        recorder.exited = vec![None; len];
+        recorder.started = Some(Instant::now());
+        recorder
+    }
+
+    fn record(&mut self, stage: usize, usage: StageUsage) {
+        if let Some(slot) = self.stages.get_mut(stage) {
+            *slot = Some(usage);
+        }
+        if !self.stages.iter().all(Option::is_some) {
+            return;
+        }
+        if self.report {
+            self.report = false;
+            self.log_report();
+        }
+        self.end();
+    }
+
+    // Traces the end of the pipeline, once all its stages finished, or once it was killed, or
+    // dropped or waited for without reaping all of them, in which case their status is unknown
+    pub(crate) fn end(&mut self) {
+        if self.ended {
+            return;
+        }
+        self.ended = true;
+        for status in self.statuses.iter_mut().filter(|x| x.is_empty()) {
+            *status = "?".into();
+        }
+        let xtrace = std::mem::take(&mut self.xtrace);
+        xtrace.write(&format!(
+            "end [{}] status {} in {:.3}s",
+            self.cmd,
+            self.statuses.join(" | "),
+            self.duration().as_secs_f64()
+        ));
+        #[cfg(feature = "tracing")]
+        self.close_span();
+    }
+
+    fn end_killed(&mut self) {
+        for status in self.statuses.iter_mut().filter(|x| x.is_empty()) {
+            *status = "killed".into();
+        }
+        self.end();
+    }
+
+    // Duration of the pipeline, until the last stage was reaped, or until now if some weren't
+    fn duration(&self) -> Duration {
+        match self.started {
+            Some(started) if self.stages.iter().any(Option::is_none) => started.elapsed(),
+            _ => self.real_time(),
+        }
+    }
+
+    fn record_status(&mut self, stage: usize, res: &Result<Option<ExitStatus>>) {
+        let status = match res {
+            Ok(Some(status)) => match StageStatus::from_exit_status(*status) {
+                StageStatus::Exited(code) => code.to_string(),
+                StageStatus::Signaled(signal) => format!("signal {signal}"),
+                _ => "error".into(),
+            },
+            Ok(None) => "0".into(),
+            Err(_) => "error".into(),
+        };
+        if let Some(slot) = self.statuses.get_mut(stage) {
+            *slot = status;
+        }
+    }
+
+    fn real_time(&self) -> Duration {
+        self.stages
+            .iter()
+            .flatten()
+            .map(|x| x.wall_time)
+            .max()
+            .unwrap_or_default()
+    }
+
+    // Logs the timing of the whole pipeline, like bash's `time`
+    fn log_report(&self) {
+        let rusages = self
+            .stages
+            .iter()
+            .flatten()
+            .filter_map(|x| x.rusage.as_ref());
+        let user = rusages.clone().map(|x| x.user_time).sum();
+        let sys = rusages.map(|x| x.sys_time).sum();
+        let format = |d: Duration| format!("{}m{:.3}s", d.as_secs() / 60, d.as_secs_f64() % 60.0);
+        info!(
+            "Time of [{}]: real {}, user {}, sys {}",
+            self.cmd,
+            format(self.real_time()),
+            format(user),
+            format(sys)
+        );
+    }
+}
+
+// Records when each stage of a pipeline exits while it is waited for, since the stages are
+// reaped from the last one, after the earlier ones may have exited long before
+fn watch_exits(usage: &Arc<Mutex<UsageRecorder>>, children: &[CmdChild]) {
+    let usage = Arc::downgrade(usage);
+    let mut pending: Vec<_> = children
+        .iter()
+        .map(|child| (child.stage, child.pid(), child.handle.cancel_token()))
+        .collect();
+    std::thread::spawn(move || {
+        while !pending.is_empty() {
+            std::thread::sleep(Duration::from_millis(5));
+            let Some(usage) = usage.upgrade() else {
+                return;
+            };
+            // pids can't be reused while peeking at them
+            let _reaping = REAPING.lock().unwrap();
+            let mut recorder = usage.lock().unwrap();
+            pending.retain(|(stage, pid, cancel)| {
+                if recorder.stages[*stage].is_some() {
+                    return false;
+                }
+                let running = match (pid, cancel) {
+                    (Some(pid), _) => peek_proc(*pid) == Some(true),
+                    (None, Some(cancel)) => !cancel.is_finished(),
+                    (None, None) => false,
+                };
+                if !running {
+                    recorder.exited[*stage] = Some(Instant::now());
+                }
+                running
+            });
+        }
+    });
+}
+
+impl Drop for UsageRecorder {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl UsageRecorder {
+    fn open_span(&mut self, span: tracing::Span) {
+        self.span = CmdSpan(Some(span));
+    }
+
+    // Records how the pipeline exited, like `PIPESTATUS`, and closes its span
+    fn close_span(&mut self) {
+        if let Some(span) = std::mem::take(&mut self.span).0 {
+            span.record("status", self.statuses.join(" | "));
+            span.record("duration_ms", self.duration().as_millis() as u64);
+        }
+    }
+}
+
+// Span of a pipeline with the `tracing` feature, which its forwarded stderr lines are emitted in
+#[derive(Clone, Default)]
+pub(crate) struct CmdSpan(#[cfg(feature = "tracing")] Option<tracing::Span>);
+
+impl CmdSpan {
+    fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        #[cfg(feature = "tracing")]
+        if let Some(span) = &self.0 {
+            return span.in_scope(f);
+        }
+        f()
+    }
+}
+
 struct StderrThread {
-    thread: Option<JoinHandle<String>>,
+    thread: Option<JoinHandle<StderrOutput>>,
     cmd: String,
     file: String,
     line: u32,
 }
 
 impl StderrThread {
-    fn new(cmd: &str, file: &str, line: u32, stderr: Option<PipeReader>, capture: bool) -> Self {
-        if let Some(stderr) = stderr {
+    fn new(child: &mut CmdChild, sink: Option<StderrSink>) -> Self {
+        let (cmd, file, line) = (&child.cmd, &child.file, child.line);
+        if let Some(stderr) = child.stderr.take() {
+            let cmd_str = cmd.to_owned();
+            let format = child.stderr_format.resolve(&child.name, child.pid());
+            let tail_lines = child.stderr_tail;
+            let span = child.usage.lock().unwrap().span.clone();
             let thread = std::thread::spawn(move || {
-                let mut output = String::new();
-                BufReader::new(stderr)
-                    .lines()
-                    .map_while(Result::ok)
-                    .for_each(|line| {
-                        if !capture {
-                            info!("{line}");
-                        } else {
-                            if !output.is_empty() {
-                                output.push('\n');
-                            }
-                            output.push_str(&line);
+                span.in_scope(|| {
+                    let mut output = StderrOutput::default();
+                    let mut stderr = BufReader::new(stderr);
+                    let mut buf = vec![];
+                    // split on bytes, so that invalid UTF-8 doesn't stop draining the pipe
+                    while let Ok(n) = stderr.read_until(b'\n', &mut buf) {
+                        if n == 0 {
+                            break;
                         }
-                    });
-                output
+                        output.push_line(&cmd_str, &buf, sink.as_ref(), &format, tail_lines);
+                        buf.clear();
+                    }
+                    output
+                })
             });
             Self {
                 cmd: cmd.into(),
@@ -371,7 +1696,7 @@ impl StderrThread {
         }
     }
 
-    fn join(&mut self) -> String {
+    fn join(&mut self) -> StderrOutput {
         if let Some(thread) = self.thread.take() {
             match thread.join() {
                 Err(e) => {
@@ -383,7 +1708,7 @@ impl StderrThread {
                 Ok(output) => return output,
             }
         }
-        "".into()
+        StderrOutput::default()
     }
 }
 
@@ -392,3 +1717,352 @@ impl Drop for StderrThread {
         self.join();
     }
 }
+
+/// Destination of the stderr output of commands, see [`set_stderr_sink`](crate::set_stderr_sink).
+///
+/// The output of commands whose stderr is captured, like with
+/// [`FunChildren::wait_with_all`], doesn't go to the sink.
+#[derive(Clone)]
+pub enum StderrSink {
+    /// Logs each line at the given level, which is what happens by default at `Info` level.
+    Log(log::Level),
+    /// Emits each line as a `tracing` event at the given level, inside the span of its command.
+    #[cfg(feature = "tracing")]
+    Tracing(log::Level),
+    /// Writes each line to the stderr of the current process, without logging prefix.
+    Passthrough,
+    /// Drops the output.
+    Discard,
+    /// Appends each line to the shared buffer, separated by newlines.
+    Capture(Arc<Mutex<String>>),
+    /// Calls the function with the command string and each line.
+    Custom(Arc<StderrSinkFn>),
+}
+
+/// Function type of [`StderrSink::Custom`], called with the command string and each line.
+pub type StderrSinkFn = dyn Fn(&str, &str) + Send + Sync;
+
+impl StderrSink {
+    /// Creates a sink calling `f` with the command string and each line.
+    pub fn custom(f: impl Fn(&str, &str) + Send + Sync + 'static) -> Self {
+        StderrSink::Custom(Arc::new(f))
+    }
+
+    fn write(&self, cmd: &str, line: &str) {
+        match self {
+            StderrSink::Log(level) => {
+                crate::try_init_default_logger();
+                log::log!(*level, "{line}");
+            }
+            #[cfg(feature = "tracing")]
+            StderrSink::Tracing(level) => match level {
+                log::Level::Error => tracing::error!(cmd, "{line}"),
+                log::Level::Warn => tracing::warn!(cmd, "{line}"),
+                log::Level::Info => tracing::info!(cmd, "{line}"),
+                log::Level::Debug => tracing::debug!(cmd, "{line}"),
+                log::Level::Trace => tracing::trace!(cmd, "{line}"),
+            },
+            StderrSink::Passthrough => {
+                let _ = writeln!(std::io::stderr(), "{line}");
+            }
+            StderrSink::Discard => {}
+            StderrSink::Capture(buf) => push_captured(&mut buf.lock().unwrap(), line),
+            StderrSink::Custom(f) => f(cmd, line),
+        }
+    }
+}
+
+impl Default for StderrSink {
+    fn default() -> Self {
+        StderrSink::Log(log::Level::Info)
+    }
+}
+
+/// Where the captured stdout output is copied to as well, see [`set_tee`](crate::set_tee).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Tee {
+    /// Not copied.
+    #[default]
+    None,
+    /// The stdout of the parent process.
+    Stdout,
+    /// A file, which is created or truncated.
+    File(PathBuf),
+}
+
+impl Tee {
+    fn read_to_end(&self, reader: &mut impl Read, buf: &mut Vec<u8>) -> Result<()> {
+        let mut out: Box<dyn Write> = match self {
+            Tee::None => return reader.read_to_end(buf).map(|_| ()),
+            Tee::Stdout => Box::new(std::io::stdout()),
+            Tee::File(path) => Box::new(File::create(path)?),
+        };
+        let mut chunk = [0; 8192];
+        loop {
+            let n = match reader.read(&mut chunk) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            buf.extend_from_slice(&chunk[..n]);
+            out.write_all(&chunk[..n])?;
+            out.flush()?;
+        }
+    }
+}
+
+/// Where the traces of commands go, like bash's `set -x`, see [`set_xtrace`](crate::set_xtrace).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Xtrace {
+    /// Not traced.
+    #[default]
+    None,
+    /// The stderr of the parent process.
+    Stderr,
+    /// A file, which is appended to.
+    File(PathBuf),
+}
+
+impl Xtrace {
+    // Writes a trace line, errors are ignored so that tracing never fails a command
+    pub(crate) fn write(&self, line: &str) {
+        if *self == Xtrace::None {
+            return;
+        }
+        let line = format!("+ {} {line}\n", utc_time());
+        let _ = match self {
+            Xtrace::None => Ok(()),
+            Xtrace::Stderr => std::io::stderr().write_all(line.as_bytes()),
+            Xtrace::File(path) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(line.as_bytes())),
+        };
+    }
+}
+
+/// Output stream of a command, see [`OutputChunk`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    /// Standard output.
+    Stdout,
+    /// Standard error.
+    Stderr,
+}
+
+/// Chunk of output read from a command, as returned by [`FunChildren::wait_with_transcript`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputChunk {
+    /// The stream it was written to.
+    pub stream: OutputStream,
+    /// The bytes read.
+    pub data: Vec<u8>,
+    /// When it was read, if timestamps were requested.
+    pub time: Option<SystemTime>,
+}
+
+impl OutputChunk {
+    /// Returns the data as text, replacing invalid UTF-8 sequences.
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.data)
+    }
+}
+
+// Reads all pipes to the end with a single poll loop, so that chunks are recorded in the order
+// they became readable
+fn read_transcript(
+    mut pipes: Vec<(OutputStream, PipeReader)>,
+    timestamps: bool,
+    transcript: &mut Vec<OutputChunk>,
+) -> Result<()> {
+    let mut buf = [0; 8192];
+    while !pipes.is_empty() {
+        let mut fds: Vec<libc::pollfd> = pipes
+            .iter()
+            .map(|(_, pipe)| libc::pollfd {
+                fd: pipe.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        // SAFETY: fds points to fds.len() initialized pollfd structs
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            let e = Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        let mut closed = vec![];
+        for (i, fd) in fds.iter().enumerate() {
+            if fd.revents == 0 {
+                continue;
+            }
+            let (stream, pipe) = &mut pipes[i];
+            match pipe.read(&mut buf) {
+                Ok(0) => closed.push(i),
+                Ok(n) => transcript.push(OutputChunk {
+                    stream: *stream,
+                    data: buf[..n].to_vec(),
+                    time: timestamps.then(SystemTime::now),
+                }),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        for i in closed.into_iter().rev() {
+            pipes.remove(i);
+        }
+    }
+    Ok(())
+}
+
+/// Label prepended to forwarded stderr lines, see [`set_stderr_prefix`](crate::set_stderr_prefix).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum StderrPrefix {
+    /// No label.
+    #[default]
+    None,
+    /// The command name.
+    Cmd,
+    /// The process id, or the command name for builtin and custom commands.
+    Pid,
+    /// A fixed tag.
+    Tag(String),
+}
+
+// How forwarded stderr lines are decorated
+#[derive(Clone)]
+pub(crate) struct LineFormat {
+    prefix: StderrPrefix,
+    timestamp: bool,
+}
+
+impl Default for LineFormat {
+    fn default() -> Self {
+        Self {
+            prefix: process::stderr_prefix(),
+            timestamp: process::stderr_timestamp_enabled(),
+        }
+    }
+}
+
+impl LineFormat {
+    // Turns `Cmd` and `Pid` prefixes into tags for the given command
+    pub(crate) fn resolve(&self, name: &str, pid: Option<u32>) -> Self {
+        let prefix = match &self.prefix {
+            StderrPrefix::Cmd => StderrPrefix::Tag(name.to_owned()),
+            StderrPrefix::Pid => {
+                StderrPrefix::Tag(pid.map_or(name.to_owned(), |pid| pid.to_string()))
+            }
+            prefix => prefix.clone(),
+        };
+        Self {
+            prefix,
+            timestamp: self.timestamp,
+        }
+    }
+
+    fn apply(&self, line: String) -> String {
+        let mut formatted = String::new();
+        if self.timestamp {
+            formatted.push_str(&utc_time());
+            formatted.push(' ');
+        }
+        if let StderrPrefix::Tag(tag) = &self.prefix {
+            formatted.push('[');
+            formatted.push_str(tag);
+            formatted.push_str("] ");
+        }
+        if formatted.is_empty() {
+            return line;
+        }
+        formatted.push_str(&line);
+        formatted
+    }
+}
+
+// Current UTC time as HH:MM:SS.mmm, since the local time zone can't be read safely while other
+// threads may set environment variables
+fn utc_time() -> String {
+    let now = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs() % 86400;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        now.subsec_millis()
+    )
+}
+
+// Decodes output lossily, without its trailing newline
+pub(crate) fn output_to_string(buf: Vec<u8>) -> String {
+    let mut output = match String::from_utf8(buf) {
+        Ok(output) => output,
+        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+    };
+    if output.ends_with('\n') {
+        output.pop();
+    }
+    output
+}
+
+fn push_captured(captured: &mut String, line: &str) {
+    if !captured.is_empty() {
+        captured.push('\n');
+    }
+    captured.push_str(line);
+}
+
+#[derive(Default)]
+pub(crate) struct StderrOutput {
+    pub(crate) captured: Vec<u8>,
+    tail: VecDeque<String>,
+}
+
+impl StderrOutput {
+    // Sends a formatted line of stderr to `sink`, or captures it as is without one, keeping
+    // the last `tail_lines` of them
+    pub(crate) fn push_line(
+        &mut self,
+        cmd: &str,
+        raw_line: &[u8],
+        sink: Option<&StderrSink>,
+        format: &LineFormat,
+        tail_lines: usize,
+    ) {
+        if sink.is_none() {
+            self.captured.extend_from_slice(raw_line);
+        }
+        let mut line = output_to_string(raw_line.to_vec());
+        if line.ends_with('\r') {
+            line.pop();
+        }
+        if tail_lines > 0 {
+            if self.tail.len() == tail_lines {
+                self.tail.pop_front();
+            }
+            self.tail.push_back(line.clone());
+        }
+        if let Some(sink) = sink {
+            sink.write(cmd, &format.apply(line));
+        }
+    }
+
+    pub(crate) fn attach_tail(&self, e: Error) -> Error {
+        if self.tail.is_empty() {
+            return e;
+        }
+        let mut msg = format!("{e}\nstderr (last {} lines):", self.tail.len());
+        for line in self.tail.iter() {
+            msg.push_str("\n    ");
+            msg.push_str(line);
+        }
+        Error::new(e.kind(), msg)
+    }
+}
@@ -1,24 +1,81 @@
 use os_pipe::*;
 use std::fs::File;
-use std::io::{Read, Result, Write};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::os::unix::io::AsRawFd;
 use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// How often blocked pipe reads check for cancellation, in milliseconds
+const CANCEL_POLL_INTERVAL: libc::c_int = 100;
+
+/// Cancellation flag shared between a custom command running as a thread and its handle.
+#[derive(Clone, Default)]
+pub(crate) struct CancelToken(Arc<CancelState>);
+
+#[derive(Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    finished: AtomicBool,
+}
+
+impl CancelToken {
+    pub(crate) fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Marks the command as returned, so that timeouts don't count it as still running
+    pub(crate) fn finish(&self) {
+        self.0.finished.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.0.finished.load(Ordering::SeqCst)
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            // not `Interrupted`, which `read_to_end()` and friends would retry forever
+            return Err(Error::other("command cancelled"));
+        }
+        Ok(())
+    }
+}
 
 /// Standard input stream for custom command implementation, which is part of [`CmdEnv`](crate::CmdEnv).
-pub struct CmdIn(CmdInInner);
+///
+/// Once the command is cancelled, reading from it returns an error, even if it is blocked
+/// waiting for input.
+pub struct CmdIn {
+    inner: CmdInInner,
+    cancel: Option<CancelToken>,
+}
 
 impl Read for CmdIn {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        match &mut self.0 {
+        if let Some(ref cancel) = self.cancel {
+            cancel.check()?;
+        }
+        match &mut self.inner {
             CmdInInner::Null => Ok(0),
             CmdInInner::File(file) => file.read(buf),
-            CmdInInner::Pipe(pipe) => pipe.read(buf),
+            CmdInInner::Pipe(pipe) => {
+                if let Some(ref cancel) = self.cancel {
+                    Self::wait_readable(pipe, cancel)?;
+                }
+                pipe.read(buf)
+            }
         }
     }
 }
 
 impl From<CmdIn> for Stdio {
     fn from(cmd_in: CmdIn) -> Stdio {
-        match cmd_in.0 {
+        match cmd_in.inner {
             CmdInInner::Null => Stdio::null(),
             CmdInInner::File(file) => Stdio::from(file),
             CmdInInner::Pipe(pipe) => Stdio::from(pipe),
@@ -28,22 +85,60 @@ impl From<CmdIn> for Stdio {
 
 impl CmdIn {
     pub(crate) fn null() -> Self {
-        Self(CmdInInner::Null)
+        Self::new(CmdInInner::Null)
     }
 
     pub(crate) fn file(f: File) -> Self {
-        Self(CmdInInner::File(f))
+        Self::new(CmdInInner::File(f))
     }
 
     pub(crate) fn pipe(p: PipeReader) -> Self {
-        Self(CmdInInner::Pipe(p))
+        Self::new(CmdInInner::Pipe(p))
+    }
+
+    fn new(inner: CmdInInner) -> Self {
+        Self {
+            inner,
+            cancel: None,
+        }
+    }
+
+    pub(crate) fn set_cancel(&mut self, cancel: CancelToken) {
+        self.cancel = Some(cancel);
     }
 
     pub fn try_clone(&self) -> Result<Self> {
-        match &self.0 {
-            CmdInInner::Null => Ok(Self(CmdInInner::Null)),
-            CmdInInner::File(file) => file.try_clone().map(|f| Self(CmdInInner::File(f))),
-            CmdInInner::Pipe(pipe) => pipe.try_clone().map(|p| Self(CmdInInner::Pipe(p))),
+        let inner = match &self.inner {
+            CmdInInner::Null => CmdInInner::Null,
+            CmdInInner::File(file) => CmdInInner::File(file.try_clone()?),
+            CmdInInner::Pipe(pipe) => CmdInInner::Pipe(pipe.try_clone()?),
+        };
+        Ok(Self {
+            inner,
+            cancel: self.cancel.clone(),
+        })
+    }
+
+    // Blocks until the pipe has data or is closed, checking for cancellation periodically
+    fn wait_readable(pipe: &PipeReader, cancel: &CancelToken) -> Result<()> {
+        let mut fds = libc::pollfd {
+            fd: pipe.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            cancel.check()?;
+            // SAFETY: fds is a single initialized pollfd struct
+            match unsafe { libc::poll(&mut fds, 1, CANCEL_POLL_INTERVAL) } {
+                0 => continue,
+                n if n > 0 => return Ok(()),
+                _ => {
+                    let e = Error::last_os_error();
+                    if e.kind() != ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+            }
         }
     }
 }
@@ -55,11 +150,19 @@ enum CmdInInner {
 }
 
 /// Standard output stream for custom command implementation, which is part of [`CmdEnv`](crate::CmdEnv).
-pub struct CmdOut(CmdOutInner);
+///
+/// Once the command is cancelled, writing to it returns an error.
+pub struct CmdOut {
+    inner: CmdOutInner,
+    cancel: Option<CancelToken>,
+}
 
 impl Write for CmdOut {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        match &mut self.0 {
+        if let Some(ref cancel) = self.cancel {
+            cancel.check()?;
+        }
+        match &mut self.inner {
             CmdOutInner::Null => Ok(buf.len()),
             CmdOutInner::File(file) => file.write(buf),
             CmdOutInner::Pipe(pipe) => pipe.write(buf),
@@ -67,7 +170,7 @@ impl Write for CmdOut {
     }
 
     fn flush(&mut self) -> Result<()> {
-        match &mut self.0 {
+        match &mut self.inner {
             CmdOutInner::Null => Ok(()),
             CmdOutInner::File(file) => file.flush(),
             CmdOutInner::Pipe(pipe) => pipe.flush(),
@@ -77,29 +180,44 @@ impl Write for CmdOut {
 
 impl CmdOut {
     pub(crate) fn null() -> Self {
-        Self(CmdOutInner::Null)
+        Self::new(CmdOutInner::Null)
     }
 
     pub(crate) fn file(f: File) -> Self {
-        Self(CmdOutInner::File(f))
+        Self::new(CmdOutInner::File(f))
     }
 
     pub(crate) fn pipe(p: PipeWriter) -> Self {
-        Self(CmdOutInner::Pipe(p))
+        Self::new(CmdOutInner::Pipe(p))
     }
 
-    pub fn try_clone(&self) -> Result<Self> {
-        match &self.0 {
-            CmdOutInner::Null => Ok(Self(CmdOutInner::Null)),
-            CmdOutInner::File(file) => file.try_clone().map(|f| Self(CmdOutInner::File(f))),
-            CmdOutInner::Pipe(pipe) => pipe.try_clone().map(|p| Self(CmdOutInner::Pipe(p))),
+    fn new(inner: CmdOutInner) -> Self {
+        Self {
+            inner,
+            cancel: None,
         }
     }
+
+    pub(crate) fn set_cancel(&mut self, cancel: CancelToken) {
+        self.cancel = Some(cancel);
+    }
+
+    pub fn try_clone(&self) -> Result<Self> {
+        let inner = match &self.inner {
+            CmdOutInner::Null => CmdOutInner::Null,
+            CmdOutInner::File(file) => CmdOutInner::File(file.try_clone()?),
+            CmdOutInner::Pipe(pipe) => CmdOutInner::Pipe(pipe.try_clone()?),
+        };
+        Ok(Self {
+            inner,
+            cancel: self.cancel.clone(),
+        })
+    }
 }
 
 impl From<CmdOut> for Stdio {
     fn from(cmd_out: CmdOut) -> Stdio {
-        match cmd_out.0 {
+        match cmd_out.inner {
             CmdOutInner::Null => Stdio::null(),
             CmdOutInner::File(file) => Stdio::from(file),
             CmdOutInner::Pipe(pipe) => Stdio::from(pipe),
@@ -196,10 +196,53 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! To get the last lines of a failed command's stderr in its error as well, use
+//! [`set_stderr_tail`](https://docs.rs/cmd_lib/latest/cmd_lib/fn.set_stderr_tail.html) (or
+//! `CMD_LIB_STDERR_TAIL=N`), or `stderr_tail(n)` on the spawned children:
+//! ```no_run
+//! # use cmd_lib::*;
+//! set_stderr_tail(5);
+//! let output = spawn_with_output!(ls /nofile)?.stderr_tail(10).wait_with_output();
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
 //! It is using rust [log crate](https://crates.io/crates/log), and you can use your actual favorite
 //! logger implementation. Notice that if you don't provide any logger, it will use env_logger to print
 //! messages from process's stderr.
 //!
+//! To send the stderr output somewhere else, like the real stderr or your own UI, use
+//! [`set_stderr_sink`](https://docs.rs/cmd_lib/latest/cmd_lib/fn.set_stderr_sink.html), or
+//! `stderr_sink(sink)` on the spawned children:
+//! ```no_run
+//! # use cmd_lib::*;
+//! set_stderr_sink(StderrSink::Passthrough);
+//! spawn!(make -j8)?
+//!     .stderr_sink(StderrSink::custom(|cmd, line| eprintln!("{cmd}: {line}")))
+//!     .wait()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! When running commands in parallel, forwarded stderr lines can be labeled with the command
+//! name, pid or a tag of your own, and a timestamp, with
+//! [`set_stderr_prefix`](https://docs.rs/cmd_lib/latest/cmd_lib/fn.set_stderr_prefix.html) and
+//! [`set_stderr_timestamp`](https://docs.rs/cmd_lib/latest/cmd_lib/fn.set_stderr_timestamp.html),
+//! or `stderr_prefix(prefix)` and `stderr_timestamp(true)` on the spawned children.
+//!
+//! With the `tracing` feature, every pipeline runs in a `cmd` span of the
+//! [tracing crate](https://crates.io/crates/tracing), with the command string, location, pids and
+//! current directory as fields, and its exit status and duration recorded once it finished, was
+//! killed or dropped. With `StderrSink::Tracing(level)`, the stderr lines of its commands are
+//! emitted as events inside that span, instead of log records.
+//!
+//! To trace the commands like bash's `set -x`, use
+//! [`set_xtrace`](https://docs.rs/cmd_lib/latest/cmd_lib/fn.set_xtrace.html) (or
+//! `CMD_LIB_XTRACE=-|<file>`), or `Shell::set_xtrace` for the commands of a session only. Each
+//! pipeline is traced when it starts and when it finishes:
+//! ```console
+//! + 10:42:07.315 start [FOO="1" "make" "-j8" | "tail" "-n" "1"] in /src/app at src/main.rs:12
+//! + 10:42:09.861 end [FOO="1" "make" "-j8" | "tail" "-n" "1"] status 0 | 0 in 2.546s
+//! ```
+//!
 //! You can also mark your `main()` function with `#[cmd_lib::main]`, which will log error from
 //! main() by default. Like this:
 //! ```console
@@ -222,18 +265,120 @@
 //! exits the scope.
 //!
 //! Use `std::env::set_current_dir` if you want to change the current
-//! working directory for the whole program.
+//! working directory for the whole program, or
+//! [`with_dir`](https://docs.rs/cmd_lib/latest/cmd_lib/fn.with_dir.html) to run all the macros
+//! of a closure in a directory, only on the current thread:
+//! ```no_run
+//! # use cmd_lib::*;
+//! let n = with_dir("/tmp", || run_fun!(ls | wc -l))?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### pushd, popd, dirs
+//! Like `cd`, but `pushd` saves the previous directory on a stack, which `popd` goes back to, and
+//! `dirs` prints the working directory followed by the stack. The stack also lasts until the end
+//! of the scope.
+//! ```no_run
+//! # use cmd_lib::run_cmd;
+//! run_cmd! (
+//!     pushd /tmp;
+//!     ls;
+//!     popd;
+//! )?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
 //!
 //! #### ignore
 //!
 //! Ignore errors for command execution.
 //!
+//! #### timeout
+//!
+//! Bound the running time of the whole pipeline, like `timeout 30s`, `timeout 500ms` or
+//! `timeout 2m` (seconds by default). Once it expires, all the processes get SIGTERM, followed by
+//! SIGKILL if they are still alive after a grace period, and an error of kind
+//! [`TimedOut`](std::io::ErrorKind::TimedOut) is returned. A duration of 0 disables the timeout.
+//! ```no_run
+//! # use cmd_lib::*;
+//! run_cmd!(timeout 30s ssh host uptime)?;
+//!
+//! // or for spawned children
+//! let mut proc = spawn!(curl -s "https://example.com")?;
+//! proc.wait_timeout(std::time::Duration::from_secs(30))?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### time
+//!
+//! Log the real, user and sys time of the whole pipeline once it finished, like bash's `time`.
+//! The timing and resource usage of each command are also available with
+//! [`usage()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.CmdChildren.html#method.usage)
+//! on spawned children, and in the outcomes of
+//! [`wait_all()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.CmdChildren.html#method.wait_all).
+//! When followed by options, like `time -v`, the external `time` command is run instead.
+//! ```no_run
+//! # use cmd_lib::*;
+//! run_cmd!(time dd if=/dev/zero of=/dev/null bs=1M count=1000)?;
+//! // output:
+//! // [INFO ] Time of ["dd" "if=/dev/zero" "of=/dev/null" "bs=1M" "count=1000"]: real 0m0.123s, user 0m0.000s, sys 0m0.122s
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### dry_run
+//!
+//! Log the pipeline with its redirects and working directory instead of running it, and succeed
+//! with empty output. Builtins without side effects, like `cd` and `echo`, still run, unless their
+//! output is captured. To do a dry run of everything, use
+//! [`set_dry_run`](https://docs.rs/cmd_lib/latest/cmd_lib/fn.set_dry_run.html) or
+//! `CMD_LIB_DRY_RUN=1`.
+//! ```no_run
+//! # use cmd_lib::*;
+//! run_cmd!(dry_run rm -rf /tmp/build > /tmp/rm.log)?;
+//! // output:
+//! // [INFO ] Dry run ["rm" "-rf" "/tmp/build" 1>"/tmp/rm.log"] in /home/user at src/main.rs:2
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### new_pgroup
+//!
+//! Run the pipeline in a new process group, so that signals sent to spawned children also reach
+//! the processes they started themselves, like the jobs of `make`. The pipeline then no longer
+//! gets Ctrl-C from the terminal, and stops if it reads from it.
+//! ```no_run
+//! # use cmd_lib::*;
+//! let mut proc = spawn!(new_pgroup make -j8)?;
+//! proc.terminate()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! #### env
+//!
+//! Run the command with `-i` an empty environment, with `-k NAME` an environment only keeping
+//! the variable `NAME` of the current one, with `-u NAME` the variable `NAME` removed from it,
+//! and with `-C dir` in another directory, relative to the working one. Variables assigned after
+//! these options are exported to the command as well. The options also apply to custom commands
+//! and to the variables of
+//! [`Shell`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.Shell.html) sessions. Without a
+//! command to run, the external `env` is run instead.
+//! ```no_run
+//! # use cmd_lib::*;
+//! run_cmd!(env -k PATH -k HOME -k LANG -C /src/app CARGO_TERM_COLOR=never cargo build)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
 //! #### echo
 //! Print messages to stdout.
 //! ```console
 //! -n     do not output the trailing newline
 //! ```
 //!
+//! #### tee
+//! Copy stdin to stdout and to each of the given files.
+//! ```console
+//! -a, --append     append to the files, do not truncate them
+//! ```
+//! With any other option, the external `tee` command is run instead.
+//!
 //! #### error, warn, info, debug, trace
 //!
 //! Print messages to logging with different levels. You can also use the normal logging macros,
@@ -260,9 +405,43 @@
 //!
 //! With [`spawn_with_output!`](https://docs.rs/cmd_lib/latest/cmd_lib/macro.spawn_with_output.html) you can get output by calling
 //! [`wait_with_output()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.FunChildren.html#method.wait_with_output),
-//! [`wait_with_all()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.FunChildren.html#method.wait_with_all)
+//! [`wait_with_all()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.FunChildren.html#method.wait_with_all),
+//! [`wait_with_transcript()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.FunChildren.html#method.wait_with_transcript)
+//! for stdout and stderr in the order they were written,
 //! or even do stream
-//! processing with [`wait_with_pipe()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.FunChildren.html#method.wait_with_pipe).
+//! processing with [`wait_with_pipe()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.FunChildren.html#method.wait_with_pipe),
+//! or with the [`lines()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.FunChildren.html#method.lines)
+//! and [`chunks()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.FunChildren.html#method.chunks) iterators.
+//!
+//! To check whether spawned children are finished without blocking, e.g. from a UI loop, use
+//! [`try_wait()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.CmdChildren.html#method.try_wait)
+//! or [`statuses()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.CmdChildren.html#method.statuses)
+//! for the status of each command in the pipeline. Similarly,
+//! [`wait_all()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.CmdChildren.html#method.wait_all)
+//! waits for the whole pipeline and returns how each command exited, like `PIPESTATUS` in bash.
+//!
+//! Spawned children can be stopped with
+//! [`signal()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.CmdChildren.html#method.signal),
+//! [`interrupt()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.CmdChildren.html#method.interrupt),
+//! [`terminate()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.CmdChildren.html#method.terminate)
+//! and [`kill()`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.CmdChildren.html#method.kill). With
+//! the `new_pgroup` prefix, they also reach the processes started by the children themselves.
+//!
+//! Dropping the handle leaves the children running, unless it was spawned with
+//! [`kill_on_drop(true)`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.CmdChildren.html#method.kill_on_drop),
+//! which terminates and reaps them when the handle goes out of scope.
+//!
+//! To watch the output of `run_fun!` or `spawn_with_output!` live while still collecting it,
+//! copy it to stdout or a file with
+//! [`set_tee`](https://docs.rs/cmd_lib/latest/cmd_lib/fn.set_tee.html) or
+//! [`tee(target)`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.FunChildren.html#method.tee)
+//! on the spawned children:
+//!
+//! ```no_run
+//! # use cmd_lib::*;
+//! let log = spawn_with_output!(cargo build)?.tee(Tee::Stdout).wait_with_output()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
 //!
 //! There are also other useful APIs, and you can check the docs for more details.
 //!
@@ -290,6 +469,28 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! ### Async macros
+//!
+//! With the `async` cargo feature, [`run_cmd_async!`](https://docs.rs/cmd_lib/latest/cmd_lib/macro.run_cmd_async.html),
+//! [`run_fun_async!`](https://docs.rs/cmd_lib/latest/cmd_lib/macro.run_fun_async.html),
+//! [`spawn_async!`](https://docs.rs/cmd_lib/latest/cmd_lib/macro.spawn_async.html) and
+//! [`spawn_with_output_async!`](https://docs.rs/cmd_lib/latest/cmd_lib/macro.spawn_with_output_async.html)
+//! run commands with [tokio](https://tokio.rs), without blocking the runtime's worker threads.
+//! Stderr is forwarded by async tasks, and custom commands run on tokio's blocking thread pool.
+//!
+//! ```no_run
+//! # use cmd_lib::*;
+//! # #[cfg(feature = "async")]
+//! # async fn f() -> CmdResult {
+//! let version = run_fun_async!(rustc --version).await?;
+//! let mut proc = spawn_async!(ping -c 10 192.168.0.1)?;
+//! // do other stuff
+//! // ...
+//! proc.wait().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
 //! ### Macro to register your own commands
 //! Declare your function with the right signature, and register it with [`use_custom_cmd!`](https://docs.rs/cmd_lib/latest/cmd_lib/macro.use_custom_cmd.html) macro:
 //!
@@ -313,6 +514,71 @@
 //! # Ok::<(), std::io::Error>(())
 //! ```
 //!
+//! To capture configuration, handles or counters, register a closure with
+//! [`register_cmd_fn`](https://docs.rs/cmd_lib/latest/cmd_lib/fn.register_cmd_fn.html), or with
+//! [`register_scoped_cmd`](https://docs.rs/cmd_lib/latest/cmd_lib/fn.register_scoped_cmd.html)
+//! to unregister it when the returned guard is dropped:
+//!
+//! ```
+//! # use cmd_lib::*;
+//! # use std::io::Write;
+//! let prefix = String::from("build:");
+//! let _guard = register_scoped_cmd("tag", move |env| {
+//!     let msg = format!("{prefix} {}", env.get_args().join(" "));
+//!     writeln!(env.stdout(), "{msg}")
+//! });
+//! assert_eq!(run_fun!(tag release)?, "build: release");
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! ### Shell sessions
+//! Each macro invocation starts in the current directory of the process, so `cd` only lasts until
+//! its end. To keep the working directory and its `pushd` stack, exported variables, options and
+//! custom commands across invocations, run them in a
+//! [`Shell`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.Shell.html) session with the
+//! `in <shell>;` prefix:
+//!
+//! ```no_run
+//! # use cmd_lib::*;
+//! let mut sh = Shell::new();
+//! sh.set_var("CARGO_TARGET_DIR", "/tmp/target");
+//! run_cmd!(in sh; cd /src/app)?;
+//! let version = run_fun!(in sh; cargo pkgid)?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! ### Mocking commands in tests
+//! To unit-test your scripts without running the real programs, install a
+//! [`Mock`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.Mock.html) in your test. Its rules take
+//! precedence over `PATH` lookup for the external commands started from the current thread, and
+//! every such command is recorded for later assertions:
+//!
+//! ```
+//! # use cmd_lib::*;
+//! let mock = Mock::new()
+//!     .on(["curl", "-s", "*"], MockOutput::default().stdout("{}"))
+//!     .on(["rsync", "**"], MockOutput::default().exit_code(23))
+//!     .install();
+//! let url = "https://example.com";
+//! assert_eq!(run_fun!(curl -s $url | wc -c)?, "2");
+//! assert!(run_cmd!(rsync -a src/ dst/).is_err());
+//! assert_eq!(mock.calls().len(), 3);
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! For golden tests, a [`Transcript`](https://docs.rs/cmd_lib/latest/cmd_lib/struct.Transcript.html)
+//! records the arguments, stdin, stdout, stderr and exit status of every external command to a
+//! human-readable file, and replays them later without running anything. A replayed script
+//! fails as soon as it runs a command different from the recorded one:
+//!
+//! ```no_run
+//! # use cmd_lib::*;
+//! let transcript = Transcript::replay("tests/golden/deploy.txt")?;
+//! run_cmd!(kubectl apply -f deploy.yaml)?;
+//! transcript.finish()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
 //! ### Macros to define, get and set thread-local global variables
 //! - [`tls_init!`](https://docs.rs/cmd_lib/latest/cmd_lib/macro.tls_init.html) to define thread local global variable
 //! - [`tls_get!`](https://docs.rs/cmd_lib/latest/cmd_lib/macro.tls_get.html) to get the value
@@ -328,6 +594,12 @@
 //!
 //! ## Other Notes
 //!
+//! ### Supported Platforms
+//!
+//! This library only supports unix platforms, like Linux, macOS and the BSDs, since process
+//! groups, signals and polling of pipes are used to manage the spawned commands. Building it for
+//! other targets fails with a compile error.
+//!
 //! ### Environment Variables
 //!
 //! You can use [std::env::var](https://doc.rust-lang.org/std/env/fn.var.html) to fetch the environment variable
@@ -369,6 +641,9 @@
 //! [`tls_init!`](https://docs.rs/cmd_lib/latest/cmd_lib/macro.tls_init.html)/[`tls_get!`](https://docs.rs/cmd_lib/latest/cmd_lib/macro.tls_get.html)/[`tls_set!`](https://docs.rs/cmd_lib/latest/cmd_lib/macro.tls_set.html) macros, and you should only use them for *thread local* variables.
 //!
 
+#[cfg(not(unix))]
+compile_error!("cmd_lib only supports unix platforms");
+
 pub use cmd_lib_macros::{
     cmd_die, main, run_cmd, run_fun, spawn, spawn_with_output, use_custom_cmd,
 };
@@ -376,19 +651,39 @@ pub use cmd_lib_macros::{
 pub type FunResult = std::io::Result<String>;
 /// Return type for [`run_cmd!()`] macro.
 pub type CmdResult = std::io::Result<()>;
-pub use child::{CmdChildren, FunChildren};
+#[cfg(feature = "async")]
+pub use async_child::{AsyncCmdChildren, AsyncFunChildren};
+pub use child::{
+    CmdChildren, FunChildren, FunChunks, FunLines, OutputChunk, OutputStream, ResourceUsage,
+    Signal, StageOutcome, StageStatus, StageUsage, StderrPrefix, StderrSink, StderrSinkFn, Tee,
+    Xtrace,
+};
+#[cfg(feature = "async")]
+pub use cmd_lib_macros::{run_cmd_async, run_fun_async, spawn_async, spawn_with_output_async};
 pub use io::{CmdIn, CmdOut};
 #[doc(hidden)]
 pub use log as inner_log;
 #[doc(hidden)]
 pub use logger::try_init_default_logger;
+pub use mock::{Mock, MockGuard, MockOutput};
 #[doc(hidden)]
 pub use process::{register_cmd, AsOsStr, Cmd, CmdString, Cmds, GroupCmds, Redirect};
-pub use process::{set_debug, set_pipefail, CmdEnv};
+pub use process::{
+    register_cmd_fn, register_scoped_cmd, set_debug, set_dry_run, set_pipefail, set_stderr_prefix,
+    set_stderr_sink, set_stderr_tail, set_stderr_timestamp, set_tee, set_xtrace, with_dir, CmdEnv,
+    CmdGuard,
+};
+pub use shell::Shell;
+pub use transcript::Transcript;
 
+#[cfg(feature = "async")]
+mod async_child;
 mod builtins;
 mod child;
 mod io;
 mod logger;
+mod mock;
 mod process;
+mod shell;
 mod thread_local;
+mod transcript;
@@ -0,0 +1,225 @@
+use crate::process::CmdFn;
+use crate::{CmdEnv, CmdResult};
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::io::{Error, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+thread_local! {
+    static MOCKS: RefCell<Vec<Installed>> = const { RefCell::new(vec![]) };
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+type Calls = Arc<Mutex<Vec<Vec<String>>>>;
+
+struct Rule {
+    pattern: Vec<String>,
+    handler: CmdFn,
+}
+
+impl Rule {
+    // `*` matches any single argument, and a trailing `**` any remaining ones
+    fn matches(&self, args: &[String]) -> bool {
+        let mut args = args.iter();
+        for (i, pattern) in self.pattern.iter().enumerate() {
+            if pattern == "**" && i == self.pattern.len() - 1 {
+                return true;
+            }
+            match args.next() {
+                Some(arg) if pattern == "*" || pattern == arg => {}
+                _ => return false,
+            }
+        }
+        args.next().is_none()
+    }
+}
+
+struct Installed {
+    id: usize,
+    rules: Vec<Rule>,
+    calls: Calls,
+}
+
+/// Canned result of a mocked command, see [`Mock::on`].
+#[derive(Debug, Clone, Default)]
+pub struct MockOutput {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    code: i32,
+}
+
+impl MockOutput {
+    /// Sets what the command writes to stdout.
+    pub fn stdout(mut self, stdout: impl AsRef<[u8]>) -> Self {
+        self.stdout = stdout.as_ref().to_vec();
+        self
+    }
+
+    /// Sets what the command writes to stderr.
+    pub fn stderr(mut self, stderr: impl AsRef<[u8]>) -> Self {
+        self.stderr = stderr.as_ref().to_vec();
+        self
+    }
+
+    /// Sets the exit code of the command, 0 by default. Other codes make it fail.
+    pub fn exit_code(mut self, code: i32) -> Self {
+        self.code = code;
+        self
+    }
+
+    fn run(&self, env: &mut CmdEnv) -> CmdResult {
+        env.stdout().write_all(&self.stdout)?;
+        env.stderr().write_all(&self.stderr)?;
+        if self.code != 0 {
+            return Err(Error::other(format!(
+                "exited with error; status code: {}",
+                self.code
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Mocks of external commands for tests, taking precedence over the programs in `PATH`.
+///
+/// Mocks are matched against the whole argument list of a command, including the program
+/// name. In patterns, `*` matches any single argument, and a trailing `**` any remaining ones.
+/// Rules added first take precedence.
+///
+/// They only apply to the commands started from the thread which installed them, so tests
+/// using them can run in parallel.
+///
+/// ```
+/// # use cmd_lib::*;
+/// let mock = Mock::new()
+///     .on(["git", "rev-parse", "HEAD"], MockOutput::default().stdout("abc123\n"))
+///     .on(["git", "**"], MockOutput::default().stderr("not mocked").exit_code(128))
+///     .install();
+/// assert_eq!(run_fun!(git rev-parse HEAD)?, "abc123");
+/// assert!(run_cmd!(git push).is_err());
+/// assert_eq!(mock.calls(), [vec!["git", "rev-parse", "HEAD"], vec!["git", "push"]]);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Default)]
+pub struct Mock {
+    rules: Vec<Rule>,
+}
+
+impl Mock {
+    /// Creates an empty set of mocks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes the commands matching `pattern` return `output`.
+    pub fn on<I, S>(self, pattern: I, output: MockOutput) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.on_run(pattern, move |env| output.run(env))
+    }
+
+    /// Makes the commands matching `pattern` run `f` instead, like a custom command.
+    pub fn on_run<I, S, F>(mut self, pattern: I, f: F) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+        F: Fn(&mut CmdEnv) -> CmdResult + Send + Sync + 'static,
+    {
+        self.rules.push(Rule {
+            pattern: pattern.into_iter().map(Into::into).collect(),
+            handler: Arc::new(f),
+        });
+        self
+    }
+
+    /// Installs the mocks for the current thread, until the returned guard is dropped.
+    ///
+    /// Mocks installed later take precedence.
+    pub fn install(self) -> MockGuard {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let calls = Calls::default();
+        MOCKS.with(|mocks| {
+            mocks.borrow_mut().push(Installed {
+                id,
+                rules: self.rules,
+                calls: calls.clone(),
+            })
+        });
+        MockGuard { id, calls }
+    }
+}
+
+/// Installed mocks, which are removed when it is dropped. See [`Mock::install`].
+#[must_use = "the mocks are uninstalled when the guard is dropped"]
+pub struct MockGuard {
+    id: usize,
+    calls: Calls,
+}
+
+impl MockGuard {
+    /// Returns the arguments of every external command started from this thread while the
+    /// mocks were installed, whether it was mocked or not.
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockGuard {
+    fn drop(&mut self) {
+        // the thread local may be gone already, if the guard is dropped while the thread exits
+        let _ = MOCKS.try_with(|mocks| mocks.borrow_mut().retain(|x| x.id != self.id));
+    }
+}
+
+// External command resolved against the installed mocks, which is recorded in them only once
+// it is started, since an earlier command may fail first
+pub(crate) struct MockCall {
+    args: Vec<String>,
+    calls: Vec<Calls>,
+    handler: Option<CmdFn>,
+}
+
+impl MockCall {
+    // Returns the handler to run instead of the external command, if a mock matched
+    pub(crate) fn handler(&self) -> Option<CmdFn> {
+        self.handler.clone()
+    }
+
+    pub(crate) fn record(self) {
+        for calls in self.calls.iter() {
+            calls.lock().unwrap().push(self.args.clone());
+        }
+    }
+}
+
+// Resolves the external command against the mocks installed for the current thread
+pub(crate) fn find(args: &[OsString]) -> Option<MockCall> {
+    MOCKS.with(|mocks| {
+        let mocks = mocks.borrow();
+        if mocks.is_empty() {
+            return None;
+        }
+        let args: Vec<String> = args
+            .iter()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        let handler = mocks
+            .iter()
+            .rev()
+            .flat_map(|installed| installed.rules.iter())
+            .find(|rule| rule.matches(&args))
+            .map(|rule| rule.handler.clone());
+        Some(MockCall {
+            calls: mocks
+                .iter()
+                .map(|installed| installed.calls.clone())
+                .collect(),
+            args,
+            handler,
+        })
+    })
+}
@@ -1,23 +1,46 @@
+#[cfg(feature = "async")]
+use crate::async_child::{AsyncCmdChild, AsyncCmdChildren, AsyncFunChildren};
 use crate::builtins::*;
-use crate::child::{CmdChild, CmdChildHandle, CmdChildren, FunChildren};
-use crate::io::{CmdIn, CmdOut};
-use crate::{debug, warn};
+use crate::child::{
+    CmdChild, CmdChildHandle, CmdChildren, FunChildren, StderrPrefix, StderrSink, Tee, Xtrace,
+};
+use crate::io::{CancelToken, CmdIn, CmdOut};
+use crate::mock::{self, MockCall};
+use crate::shell::Shell;
+use crate::transcript;
+use crate::{debug, info, warn};
 use crate::{CmdResult, FunResult};
 use faccess::{AccessMode, PathExt};
 use lazy_static::lazy_static;
 use os_pipe::{self, PipeReader, PipeWriter};
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::io::{Error, ErrorKind, Result};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 const CD_CMD: &str = "cd";
+const PUSHD_CMD: &str = "pushd";
+const POPD_CMD: &str = "popd";
+const DIRS_CMD: &str = "dirs";
 const IGNORE_CMD: &str = "ignore";
+const TIMEOUT_CMD: &str = "timeout";
+const TIME_CMD: &str = "time";
+const DRY_RUN_CMD: &str = "dry_run";
+const NEW_PGROUP_CMD: &str = "new_pgroup";
+const TEE_CMD: &str = "tee";
+const ENV_CMD: &str = "env";
+// builtins which still run in dry runs, as long as they are not redirected
+const DRY_RUN_SAFE_CMDS: &[&str] = &[
+    CD_CMD, PUSHD_CMD, POPD_CMD, DIRS_CMD, "echo", "error", "warn", "info", "debug", "trace", "",
+];
 
 /// Environment for builtin or custom commands.
 pub struct CmdEnv {
@@ -27,6 +50,7 @@ pub struct CmdEnv {
     args: Vec<String>,
     vars: HashMap<String, String>,
     current_dir: PathBuf,
+    cancel: CancelToken,
 }
 impl CmdEnv {
     /// Returns the name of this command.
@@ -63,29 +87,134 @@ impl CmdEnv {
     pub fn stderr(&mut self) -> &mut CmdOut {
         &mut self.stderr
     }
+
+    /// Returns true once this command has been asked to stop, by killing its children handle
+    /// or by a timeout. Long running commands should check it and return early.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    // Borrows all the streams at once, to pump them from different threads
+    pub(crate) fn streams(&mut self) -> (&mut CmdIn, &mut CmdOut, &mut CmdOut, &CancelToken) {
+        (
+            &mut self.stdin,
+            &mut self.stdout,
+            &mut self.stderr,
+            &self.cancel,
+        )
+    }
+
+    pub(crate) fn set_cancel(&mut self, cancel: &CancelToken) {
+        self.cancel = cancel.clone();
+        self.stdin.set_cancel(cancel.clone());
+        self.stdout.set_cancel(cancel.clone());
+        self.stderr.set_cancel(cancel.clone());
+    }
 }
 
 type FnFun = fn(&mut CmdEnv) -> CmdResult;
+pub(crate) type CmdFn = Arc<dyn Fn(&mut CmdEnv) -> CmdResult + Send + Sync>;
 
 lazy_static! {
-    static ref CMD_MAP: Mutex<HashMap<OsString, FnFun>> = {
+    static ref CMD_MAP: Mutex<HashMap<OsString, CmdFn>> = {
         // needs explicit type, or it won't compile
-        let mut m: HashMap<OsString, FnFun> = HashMap::new();
-        m.insert("echo".into(), builtin_echo);
-        m.insert("trace".into(), builtin_trace);
-        m.insert("debug".into(), builtin_debug);
-        m.insert("info".into(), builtin_info);
-        m.insert("warn".into(), builtin_warn);
-        m.insert("error".into(), builtin_error);
-        m.insert("".into(), builtin_empty);
+        let mut m: HashMap<OsString, CmdFn> = HashMap::new();
+        m.insert("echo".into(), Arc::new(builtin_echo));
+        m.insert("trace".into(), Arc::new(builtin_trace));
+        m.insert("debug".into(), Arc::new(builtin_debug));
+        m.insert("info".into(), Arc::new(builtin_info));
+        m.insert("warn".into(), Arc::new(builtin_warn));
+        m.insert("error".into(), Arc::new(builtin_error));
+        m.insert(TEE_CMD.into(), Arc::new(builtin_tee));
+        m.insert("".into(), Arc::new(builtin_empty));
 
         Mutex::new(m)
     };
+    static ref STDERR_SINK: Mutex<StderrSink> = Mutex::new(StderrSink::default());
 }
 
 #[doc(hidden)]
 pub fn register_cmd(cmd: &'static str, func: FnFun) {
-    CMD_MAP.lock().unwrap().insert(OsString::from(cmd), func);
+    CMD_MAP
+        .lock()
+        .unwrap()
+        .insert(OsString::from(cmd), Arc::new(func));
+}
+
+/// Registers a custom command for all the threads, which can capture state unlike the functions
+/// of [`use_custom_cmd!`](crate::use_custom_cmd).
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// # use std::io::Write;
+/// # use std::sync::atomic::{AtomicUsize, Ordering};
+/// # use std::sync::Arc;
+/// let count = Arc::new(AtomicUsize::new(0));
+/// let counter = count.clone();
+/// register_cmd_fn("count", move |env| {
+///     let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+///     writeln!(env.stdout(), "{n}")
+/// });
+/// run_cmd!(count; count)?;
+/// assert_eq!(count.load(Ordering::SeqCst), 2);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn register_cmd_fn<F>(name: &str, func: F)
+where
+    F: Fn(&mut CmdEnv) -> CmdResult + Send + Sync + 'static,
+{
+    CMD_MAP.lock().unwrap().insert(name.into(), Arc::new(func));
+}
+
+/// Registers a custom command like [`register_cmd_fn`], until the returned guard is dropped.
+///
+/// The command registered before under the same name, if any, is then restored. Meanwhile, the
+/// command is also registered for the other threads.
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// # use std::io::Write;
+/// let db_url = String::from("postgres://localhost/test");
+/// let _guard = register_scoped_cmd("db_url", move |env| writeln!(env.stdout(), "{db_url}"));
+/// let url = run_fun!(db_url)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn register_scoped_cmd<F>(name: &str, func: F) -> CmdGuard
+where
+    F: Fn(&mut CmdEnv) -> CmdResult + Send + Sync + 'static,
+{
+    let func: CmdFn = Arc::new(func);
+    let prev = CMD_MAP.lock().unwrap().insert(name.into(), func.clone());
+    CmdGuard {
+        name: name.into(),
+        func,
+        prev,
+    }
+}
+
+/// Guard of a custom command registered by [`register_scoped_cmd`], unregistering it on drop.
+#[must_use = "the command is unregistered when the guard is dropped"]
+pub struct CmdGuard {
+    name: OsString,
+    func: CmdFn,
+    prev: Option<CmdFn>,
+}
+
+impl Drop for CmdGuard {
+    fn drop(&mut self) {
+        let mut cmd_map = CMD_MAP.lock().unwrap();
+        // leave the command alone if it was registered again meanwhile
+        if !cmd_map
+            .get(&self.name)
+            .is_some_and(|func| Arc::ptr_eq(func, &self.func))
+        {
+            return;
+        }
+        match self.prev.take() {
+            Some(prev) => cmd_map.insert(self.name.clone(), prev),
+            None => cmd_map.remove(&self.name),
+        };
+    }
 }
 
 /// Set debug mode or not, false by default.
@@ -102,19 +231,204 @@ pub fn set_pipefail(enable: bool) {
     std::env::set_var("CMD_LIB_PIPEFAIL", if enable { "1" } else { "0" });
 }
 
+/// Set how many trailing lines of each command's stderr are attached to its error, 0 (disabled)
+/// by default.
+///
+/// Setting environment variable CMD_LIB_STDERR_TAIL=N has the same effect
+pub fn set_stderr_tail(lines: usize) {
+    std::env::set_var("CMD_LIB_STDERR_TAIL", lines.to_string());
+}
+
+/// Set where the stderr output of commands goes, which is logged at info level by default.
+///
+/// It can be overridden for spawned children with
+/// [`CmdChildren::stderr_sink`](crate::CmdChildren::stderr_sink).
+pub fn set_stderr_sink(sink: StderrSink) {
+    *STDERR_SINK.lock().unwrap() = sink;
+}
+
+/// Set the label prepended to forwarded stderr lines, none by default.
+///
+/// Setting environment variable `CMD_LIB_STDERR_PREFIX=cmd|pid|<tag>` has the same effect
+pub fn set_stderr_prefix(prefix: StderrPrefix) {
+    let value = match prefix {
+        StderrPrefix::None => "",
+        StderrPrefix::Cmd => "cmd",
+        StderrPrefix::Pid => "pid",
+        StderrPrefix::Tag(ref tag) => tag,
+    };
+    std::env::set_var("CMD_LIB_STDERR_PREFIX", value);
+}
+
+/// Set dry-run mode or not, false by default.
+///
+/// In dry-run mode commands are logged with their redirects and working directory instead of
+/// being run, and succeed with empty output. Builtins without side effects, like `cd` and `echo`,
+/// still run, unless their output is captured. It can also be enabled for a single command with the `dry_run` prefix.
+///
+/// Setting environment variable CMD_LIB_DRY_RUN=0|1 has the same effect
+pub fn set_dry_run(enable: bool) {
+    std::env::set_var("CMD_LIB_DRY_RUN", if enable { "1" } else { "0" });
+}
+
+/// Set whether forwarded stderr lines start with a UTC timestamp or not, false by default.
+///
+/// Setting environment variable CMD_LIB_STDERR_TIMESTAMP=0|1 has the same effect
+pub fn set_stderr_timestamp(enable: bool) {
+    std::env::set_var("CMD_LIB_STDERR_TIMESTAMP", if enable { "1" } else { "0" });
+}
+
+/// Set where the stdout output captured by `run_fun!` and `spawn_with_output!` is copied to
+/// as well, not copied by default.
+///
+/// It can be overridden for spawned children with [`FunChildren::tee`].
+///
+/// Setting environment variable `CMD_LIB_TEE=-|<file>` has the same effect, with `-` for stdout
+pub fn set_tee(tee: Tee) {
+    match tee {
+        Tee::None => std::env::set_var("CMD_LIB_TEE", ""),
+        Tee::Stdout => std::env::set_var("CMD_LIB_TEE", "-"),
+        Tee::File(path) => std::env::set_var("CMD_LIB_TEE", path),
+    }
+}
+
+/// Set where commands are traced, like bash's `set -x`, not traced by default.
+///
+/// Each pipeline is traced when it starts, with its environment variables and working
+/// directory, then when it finishes, with the exit status of each command and how long it took.
+/// Traces start with a UTC timestamp, and are only written for commands which are not run
+/// asynchronously.
+///
+/// Setting environment variable `CMD_LIB_XTRACE=-|<file>` has the same effect, with `-` for stderr
+pub fn set_xtrace(xtrace: Xtrace) {
+    match xtrace {
+        Xtrace::None => std::env::set_var("CMD_LIB_XTRACE", ""),
+        Xtrace::Stderr => std::env::set_var("CMD_LIB_XTRACE", "-"),
+        Xtrace::File(path) => std::env::set_var("CMD_LIB_XTRACE", path),
+    }
+}
+
 pub(crate) fn debug_enabled() -> bool {
     std::env::var("CMD_LIB_DEBUG") == Ok("1".into())
 }
 
+pub(crate) fn dry_run_enabled() -> bool {
+    std::env::var("CMD_LIB_DRY_RUN") == Ok("1".into())
+}
+
 pub(crate) fn pipefail_enabled() -> bool {
     std::env::var("CMD_LIB_PIPEFAIL") != Ok("0".into())
 }
 
+pub(crate) fn stderr_sink() -> StderrSink {
+    STDERR_SINK.lock().unwrap().clone()
+}
+
+pub(crate) fn stderr_prefix() -> StderrPrefix {
+    match std::env::var("CMD_LIB_STDERR_PREFIX") {
+        Ok(prefix) => match prefix.as_str() {
+            "" => StderrPrefix::None,
+            "cmd" => StderrPrefix::Cmd,
+            "pid" => StderrPrefix::Pid,
+            _ => StderrPrefix::Tag(prefix),
+        },
+        Err(_) => StderrPrefix::None,
+    }
+}
+
+pub(crate) fn stderr_timestamp_enabled() -> bool {
+    std::env::var("CMD_LIB_STDERR_TIMESTAMP") == Ok("1".into())
+}
+
+pub(crate) fn tee() -> Tee {
+    match std::env::var_os("CMD_LIB_TEE") {
+        Some(tee) if tee.is_empty() => Tee::None,
+        Some(tee) if tee == "-" => Tee::Stdout,
+        Some(tee) => Tee::File(tee.into()),
+        None => Tee::None,
+    }
+}
+
+pub(crate) fn xtrace() -> Xtrace {
+    match std::env::var_os("CMD_LIB_XTRACE") {
+        Some(xtrace) if xtrace.is_empty() => Xtrace::None,
+        Some(xtrace) if xtrace == "-" => Xtrace::Stderr,
+        Some(xtrace) => Xtrace::File(xtrace.into()),
+        None => Xtrace::None,
+    }
+}
+
+pub(crate) fn stderr_tail_lines() -> usize {
+    std::env::var("CMD_LIB_STDERR_TAIL")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0)
+}
+
+// Working directory of a group of commands, with the directories saved by `pushd`
+#[derive(Clone, Default)]
+pub(crate) struct WorkDirs {
+    // empty for the current directory of the process
+    pub(crate) current: PathBuf,
+    pub(crate) stack: Vec<PathBuf>,
+}
+
+thread_local! {
+    // directory set by `with_dir` for the macros run on this thread
+    static SCOPED_DIR: RefCell<PathBuf> = const { RefCell::new(PathBuf::new()) };
+}
+
+/// Runs a closure with all the commands of the cmd_lib macros called on the current thread
+/// starting in `dir`, without changing the current directory of the process.
+///
+/// A relative `dir` is resolved against the enclosing `with_dir` scope, if any. The previous
+/// directory is restored when the closure returns, even by panicking.
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// with_dir("/tmp", || -> CmdResult {
+///     run_cmd!(mkdir -p build)?;
+///     with_dir("build", || run_cmd!(touch stamp))
+/// })?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn with_dir<P: AsRef<Path>, T>(dir: P, f: impl FnOnce() -> T) -> T {
+    struct Restore(PathBuf);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let prev = std::mem::take(&mut self.0);
+            SCOPED_DIR.with(|dir| *dir.borrow_mut() = prev);
+        }
+    }
+
+    let prev = SCOPED_DIR.with(|scoped| {
+        let dir = scoped.borrow().join(dir);
+        scoped.replace(dir)
+    });
+    let _restore = Restore(prev);
+    f()
+}
+
+pub(crate) fn scoped_dir() -> PathBuf {
+    SCOPED_DIR.with(|dir| dir.borrow().clone())
+}
+
 #[doc(hidden)]
-#[derive(Default)]
 pub struct GroupCmds {
     group_cmds: Vec<Cmds>,
-    current_dir: PathBuf,
+    dirs: WorkDirs,
+}
+
+impl Default for GroupCmds {
+    fn default() -> Self {
+        Self {
+            group_cmds: Vec::new(),
+            dirs: WorkDirs {
+                current: scoped_dir(),
+                stack: Vec::new(),
+            },
+        }
+    }
 }
 
 impl GroupCmds {
@@ -125,7 +439,7 @@ impl GroupCmds {
 
     pub fn run_cmd(&mut self) -> CmdResult {
         for cmds in self.group_cmds.iter_mut() {
-            if let Err(e) = cmds.run_cmd(&mut self.current_dir) {
+            if let Err(e) = cmds.run_cmd(&mut self.dirs) {
                 if !cmds.ignore_error {
                     return Err(e);
                 }
@@ -139,7 +453,7 @@ impl GroupCmds {
         let mut last_cmd = self.group_cmds.pop().unwrap();
         self.run_cmd()?;
         // run last function command
-        let ret = last_cmd.run_fun(&mut self.current_dir);
+        let ret = last_cmd.run_fun(&mut self.dirs);
         if ret.is_err() && last_cmd.ignore_error {
             return Ok("".into());
         }
@@ -149,12 +463,75 @@ impl GroupCmds {
     pub fn spawn(mut self, with_output: bool) -> Result<CmdChildren> {
         assert_eq!(self.group_cmds.len(), 1);
         let mut cmds = self.group_cmds.pop().unwrap();
-        cmds.spawn(&mut self.current_dir, with_output)
+        cmds.spawn(&mut self.dirs, with_output)
     }
 
     pub fn spawn_with_output(self) -> Result<FunChildren> {
         self.spawn(true).map(CmdChildren::into_fun_children)
     }
+
+    // Runs the commands in the session's directory, with its variables, options and commands
+    pub(crate) fn in_shell(mut self, sh: &Shell) -> Self {
+        // until its first `cd`, the session follows `with_dir` scopes
+        if !sh.current_dir.as_os_str().is_empty() {
+            self.dirs.current = sh.current_dir.clone();
+        }
+        self.dirs.stack = sh.dir_stack.clone();
+        for cmds in self.group_cmds.iter_mut() {
+            cmds.apply_shell(sh);
+        }
+        self
+    }
+
+    pub(crate) fn into_dirs(self) -> WorkDirs {
+        self.dirs
+    }
+}
+
+#[cfg(feature = "async")]
+impl GroupCmds {
+    pub async fn run_cmd_async(mut self) -> CmdResult {
+        self.exec_cmd_async().await
+    }
+
+    pub async fn run_fun_async(mut self) -> FunResult {
+        self.exec_fun_async().await
+    }
+
+    // Runs the commands, keeping the group for its current directory
+    pub(crate) async fn exec_cmd_async(&mut self) -> CmdResult {
+        for cmds in self.group_cmds.iter_mut() {
+            if let Err(e) = cmds.run_cmd_async(&mut self.dirs).await {
+                if !cmds.ignore_error {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn exec_fun_async(&mut self) -> FunResult {
+        // run previous commands
+        let mut last_cmd = self.group_cmds.pop().unwrap();
+        self.exec_cmd_async().await?;
+        // run last function command
+        let ret = last_cmd.run_fun_async(&mut self.dirs).await;
+        if ret.is_err() && last_cmd.ignore_error {
+            return Ok("".into());
+        }
+        ret
+    }
+
+    pub fn spawn_async(mut self, with_output: bool) -> Result<AsyncCmdChildren> {
+        assert_eq!(self.group_cmds.len(), 1);
+        let mut cmds = self.group_cmds.pop().unwrap();
+        cmds.spawn_async(&mut self.dirs, with_output)
+    }
+
+    pub fn spawn_with_output_async(self) -> Result<AsyncFunChildren> {
+        self.spawn_async(true)
+            .map(AsyncCmdChildren::into_fun_children)
+    }
 }
 
 #[doc(hidden)]
@@ -163,6 +540,12 @@ pub struct Cmds {
     cmds: Vec<Option<Cmd>>,
     full_cmds: String,
     ignore_error: bool,
+    timeout: Option<Duration>,
+    time: bool,
+    dry_run: bool,
+    new_pgroup: bool,
+    pipefail: Option<bool>,
+    xtrace: Option<Xtrace>,
     file: String,
     line: u32,
 }
@@ -176,7 +559,7 @@ impl Cmds {
             self.full_cmds += " | ";
         }
         self.full_cmds += &cmd.cmd_str();
-        let (ignore_error, cmd) = cmd.gen_command();
+        let (ignore_error, mut cmd) = cmd.gen_command();
         if ignore_error {
             if self.cmds.is_empty() {
                 // first command in the pipe
@@ -188,55 +571,352 @@ impl Cmds {
                 );
             }
         }
+        if let Some(timeout) = cmd.timeout.take() {
+            if self.cmds.is_empty() {
+                // the whole pipe is bounded by the first command's timeout
+                self.timeout = Some(timeout);
+            } else {
+                warn!(
+                    "Builtin {TIMEOUT_CMD:?} command at wrong position ({}:{})",
+                    self.file, self.line
+                );
+            }
+        }
+        if cmd.dry_run {
+            if self.cmds.is_empty() {
+                self.dry_run = true;
+            } else {
+                warn!(
+                    "Builtin {DRY_RUN_CMD:?} command at wrong position ({}:{})",
+                    self.file, self.line
+                );
+            }
+        }
+        if cmd.new_pgroup {
+            if self.cmds.is_empty() {
+                self.new_pgroup = true;
+            } else {
+                warn!(
+                    "Builtin {NEW_PGROUP_CMD:?} command at wrong position ({}:{})",
+                    self.file, self.line
+                );
+            }
+        }
+        if cmd.time {
+            if self.cmds.is_empty() {
+                // like in bash, the whole pipe is timed
+                self.time = true;
+            } else {
+                warn!(
+                    "Builtin {TIME_CMD:?} command at wrong position ({}:{})",
+                    self.file, self.line
+                );
+            }
+        }
         self.cmds.push(Some(cmd));
         self
     }
 
-    fn spawn(&mut self, current_dir: &mut PathBuf, with_output: bool) -> Result<CmdChildren> {
+    // With the `new_pgroup` prefix, pipelines run in their own process group, so they can be
+    // signaled as a whole. Otherwise they stay in the caller's group to keep terminal input and
+    // Ctrl-C working.
+    fn spawn(&mut self, dirs: &mut WorkDirs, with_output: bool) -> Result<CmdChildren> {
         let full_cmds = self.full_cmds.clone();
         let file = self.file.clone();
         let line = self.line;
         if debug_enabled() {
             debug!("Running [{full_cmds}] at {file}:{line} ...");
         }
+        let xtrace = self.xtrace.clone().unwrap_or_else(xtrace);
+        xtrace.write(&format!(
+            "start [{full_cmds}] in {} at {file}:{line}",
+            working_dir(&dirs.current).display()
+        ));
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "cmd",
+            cmd = %full_cmds,
+            file = %file,
+            line,
+            dir = %working_dir(&dirs.current).display(),
+            pids = tracing::field::Empty,
+            status = tracing::field::Empty,
+            duration_ms = tracing::field::Empty,
+        );
 
         // spawning all the sub-processes
         let mut children: Vec<CmdChild> = Vec::new();
         let len = self.cmds.len();
         let mut prev_pipe_in = None;
-        for (i, cmd_opt) in self.cmds.iter_mut().enumerate() {
-            let mut cmd = cmd_opt.take().unwrap();
-            if i != len - 1 {
-                // not the last, update redirects
-                let (pipe_reader, pipe_writer) =
-                    os_pipe::pipe().map_err(|e| new_cmd_io_error(&e, &full_cmds, &file, line))?;
-                cmd.setup_redirects(&mut prev_pipe_in, Some(pipe_writer), with_output)
-                    .map_err(|e| new_cmd_io_error(&e, &full_cmds, &file, line))?;
-                prev_pipe_in = Some(pipe_reader);
+        let mut pgid = None;
+        for (i, cmd) in self
+            .take_cmds(&dirs.current, with_output)
+            .into_iter()
+            .enumerate()
+        {
+            let stage = cmd.cmd_str();
+            let pgroup = if self.new_pgroup {
+                Some(pgid.unwrap_or(0))
             } else {
-                cmd.setup_redirects(&mut prev_pipe_in, None, with_output)
-                    .map_err(|e| new_cmd_io_error(&e, &full_cmds, &file, line))?;
+                None
+            };
+            let is_last = i == len - 1;
+            match Self::prepare_stage(cmd, is_last, &mut prev_pipe_in, dirs, with_output, pgroup)
+                .and_then(|prepared| prepared.spawn(with_output))
+            {
+                Ok(child) => {
+                    if self.new_pgroup && pgid.is_none() {
+                        // the first process is the group leader
+                        pgid = child.pid();
+                    }
+                    children.push(child);
+                }
+                Err(e) => {
+                    // don't leave the already started stages running, blocked on the pipes
+                    drop(prev_pipe_in.take());
+                    CmdChildren::new(
+                        children,
+                        false,
+                        full_cmds.clone(),
+                        file.clone(),
+                        line,
+                        None,
+                        pgid,
+                    )
+                    .abort();
+                    let e = self.stage_error(&e, i, &stage);
+                    xtrace.write(&format!("failed [{full_cmds}]: {e}"));
+                    return Err(e);
+                }
+            }
+        }
+
+        let children = CmdChildren::new(
+            children,
+            self.ignore_error,
+            full_cmds,
+            file,
+            line,
+            self.timeout,
+            pgid,
+        )
+        .report_time(self.time)
+        .pipefail(self.pipefail.unwrap_or_else(pipefail_enabled))
+        .xtrace(xtrace);
+        #[cfg(feature = "tracing")]
+        let children = children.trace(span);
+        Ok(children)
+    }
+
+    fn apply_shell(&mut self, sh: &Shell) {
+        self.dry_run |= sh.dry_run;
+        self.pipefail = sh.pipefail;
+        self.xtrace.clone_from(&sh.xtrace);
+        for cmd in self.cmds.iter_mut().flatten() {
+            cmd.apply_shell(sh);
+        }
+    }
+
+    pub(crate) fn take_cmds(&mut self, current_dir: &Path, with_output: bool) -> Vec<Cmd> {
+        let mut cmds: Vec<Cmd> = self
+            .cmds
+            .iter_mut()
+            .map(|cmd| cmd.take().unwrap())
+            .collect();
+        if self.dry_run || dry_run_enabled() {
+            info!(
+                "Dry run [{}] in {} at {}:{}",
+                self.full_cmds,
+                working_dir(current_dir).display(),
+                self.file,
+                self.line
+            );
+            let len = cmds.len();
+            for (i, cmd) in cmds.iter_mut().enumerate() {
+                // captured output is empty in a dry run
+                cmd.dry_run(with_output && i == len - 1);
             }
-            let child = cmd
-                .spawn(current_dir, with_output)
-                .map_err(|e| new_cmd_io_error(&e, &full_cmds, &file, line))?;
-            children.push(child);
         }
+        cmds
+    }
+
+    pub(crate) fn prepare_stage(
+        mut cmd: Cmd,
+        is_last: bool,
+        prev_pipe_in: &mut Option<PipeReader>,
+        dirs: &mut WorkDirs,
+        with_output: bool,
+        pgroup: Option<u32>,
+    ) -> Result<PreparedCmd> {
+        if is_last {
+            cmd.setup_redirects(prev_pipe_in, None, with_output)?;
+        } else {
+            // not the last, update redirects
+            let (pipe_reader, pipe_writer) = os_pipe::pipe()?;
+            cmd.setup_redirects(prev_pipe_in, Some(pipe_writer), with_output)?;
+            *prev_pipe_in = Some(pipe_reader);
+        }
+        cmd.prepare(dirs, pgroup)
+    }
 
-        Ok(CmdChildren::new(children, self.ignore_error))
+    pub(crate) fn stage_error(&self, e: &Error, i: usize, stage: &str) -> Error {
+        if self.cmds.len() == 1 {
+            return new_cmd_io_error(e, &self.full_cmds, &self.file, self.line);
+        }
+        Error::new(
+            e.kind(),
+            format!(
+                "Running [{}] failed at stage {} [{stage}]: {e} at {}:{}",
+                self.full_cmds,
+                i + 1,
+                self.file,
+                self.line
+            ),
+        )
     }
 
-    fn spawn_with_output(&mut self, current_dir: &mut PathBuf) -> Result<FunChildren> {
-        self.spawn(current_dir, true)
-            .map(CmdChildren::into_fun_children)
+    fn spawn_with_output(&mut self, dirs: &mut WorkDirs) -> Result<FunChildren> {
+        self.spawn(dirs, true).map(CmdChildren::into_fun_children)
+    }
+
+    fn run_cmd(&mut self, dirs: &mut WorkDirs) -> CmdResult {
+        self.spawn(dirs, false)?.wait()
+    }
+
+    fn run_fun(&mut self, dirs: &mut WorkDirs) -> FunResult {
+        self.spawn_with_output(dirs)?.wait_with_output()
+    }
+}
+
+// A command ready to be started
+pub(crate) struct PreparedCmd {
+    pub(crate) task: CmdTask,
+    pub(crate) cmd_str: String,
+    pub(crate) name: String,
+    pub(crate) file: String,
+    pub(crate) line: u32,
+    pub(crate) stdout: Option<PipeReader>,
+    pub(crate) stderr: Option<PipeReader>,
+}
+
+impl PreparedCmd {
+    pub(crate) fn spawn(self, with_output: bool) -> Result<CmdChild> {
+        let pipe_out = self.stdout.is_none();
+        let handle = match self.task {
+            CmdTask::Done => CmdChildHandle::SyncFn,
+            CmdTask::Builtin(internal_cmd, mut env) => {
+                if pipe_out || with_output {
+                    let cancel = CancelToken::default();
+                    env.set_cancel(&cancel);
+                    let finished = cancel.clone();
+                    let handle = thread::Builder::new().spawn(move || {
+                        let res = internal_cmd(&mut env);
+                        finished.finish();
+                        res
+                    })?;
+                    CmdChildHandle::Thread(handle, cancel)
+                } else {
+                    internal_cmd(&mut env)?;
+                    CmdChildHandle::SyncFn
+                }
+            }
+            CmdTask::Proc(mut cmd) => CmdChildHandle::Proc(cmd.spawn()?),
+        };
+        Ok(CmdChild::new(
+            handle,
+            self.cmd_str,
+            self.name,
+            self.file,
+            self.line,
+            self.stdout,
+            self.stderr,
+        ))
+    }
+}
+
+pub(crate) enum CmdTask {
+    // already run, like `cd`
+    Done,
+    Builtin(CmdFn, CmdEnv),
+    Proc(Command),
+}
+
+#[cfg(feature = "async")]
+impl Cmds {
+    fn spawn_async(&mut self, dirs: &mut WorkDirs, with_output: bool) -> Result<AsyncCmdChildren> {
+        if debug_enabled() {
+            debug!(
+                "Running [{}] at {}:{} ...",
+                self.full_cmds, self.file, self.line
+            );
+        }
+
+        // spawning all the sub-processes
+        let mut children: Vec<AsyncCmdChild> = Vec::new();
+        let len = self.cmds.len();
+        let mut prev_pipe_in = None;
+        let mut pgid = None;
+        for (i, cmd) in self
+            .take_cmds(&dirs.current, with_output)
+            .into_iter()
+            .enumerate()
+        {
+            let stage = cmd.cmd_str();
+            let pgroup = if self.new_pgroup {
+                Some(pgid.unwrap_or(0))
+            } else {
+                None
+            };
+            let is_last = i == len - 1;
+            match Self::prepare_stage(cmd, is_last, &mut prev_pipe_in, dirs, with_output, pgroup)
+                .and_then(AsyncCmdChild::spawn)
+            {
+                Ok(child) => {
+                    if self.new_pgroup && pgid.is_none() {
+                        // the first process is the group leader
+                        pgid = child.pid();
+                    }
+                    children.push(child);
+                }
+                Err(e) => {
+                    // killed stages are reaped by the runtime in the background
+                    drop(prev_pipe_in.take());
+                    let _ = AsyncCmdChildren::new(
+                        children,
+                        false,
+                        self.full_cmds.clone(),
+                        self.file.clone(),
+                        self.line,
+                        None,
+                        pgid,
+                    )
+                    .kill();
+                    return Err(self.stage_error(&e, i, &stage));
+                }
+            }
+        }
+
+        Ok(AsyncCmdChildren::new(
+            children,
+            self.ignore_error,
+            self.full_cmds.clone(),
+            self.file.clone(),
+            self.line,
+            self.timeout,
+            pgid,
+        )
+        .pipefail(self.pipefail.unwrap_or_else(pipefail_enabled)))
     }
 
-    fn run_cmd(&mut self, current_dir: &mut PathBuf) -> CmdResult {
-        self.spawn(current_dir, false)?.wait()
+    async fn run_cmd_async(&mut self, dirs: &mut WorkDirs) -> CmdResult {
+        self.spawn_async(dirs, false)?.wait().await
     }
 
-    fn run_fun(&mut self, current_dir: &mut PathBuf) -> FunResult {
-        self.spawn_with_output(current_dir)?.wait_with_output()
+    async fn run_fun_async(&mut self, dirs: &mut WorkDirs) -> FunResult {
+        self.spawn_async(dirs, true)?
+            .into_fun_children()
+            .wait_with_output()
+            .await
     }
 }
 
@@ -272,10 +952,46 @@ impl fmt::Debug for Redirect {
     }
 }
 
+// `env` prefix, changing the environment and directory of the command
+#[derive(Default)]
+struct EnvPrefix {
+    // as written, to run the external `env` command when no command follows
+    args: Vec<OsString>,
+    // option still waiting for its value
+    pending: Option<&'static str>,
+    clear: bool,
+    unset: Vec<String>,
+    dir: Option<PathBuf>,
+    // variables assigned after `env`
+    vars: Vec<String>,
+    // variables kept from the parent environment with `-k`, and where the options end in `args`
+    kept: Vec<OsString>,
+    options_end: usize,
+}
+
+impl EnvPrefix {
+    fn hides(&self, key: &str) -> bool {
+        self.clear || self.unset.iter().any(|name| name == key)
+    }
+}
+
 #[doc(hidden)]
 pub struct Cmd {
     // for parsing
     in_cmd_map: bool,
+    timeout: Option<Duration>,
+    timeout_pending: bool,
+    time: bool,
+    time_pending: bool,
+    dry_run: bool,
+    new_pgroup: bool,
+    env: Option<EnvPrefix>,
+    // not run for real, in a dry run
+    skipped: bool,
+    // run instead of the external command, by mocks or transcripts
+    func: Option<CmdFn>,
+    // recorded in the installed mocks once started
+    mock_call: Option<MockCall>,
     args: Vec<OsString>,
     vars: HashMap<String, String>,
     redirects: Vec<Redirect>,
@@ -295,6 +1011,16 @@ impl Default for Cmd {
     fn default() -> Self {
         Cmd {
             in_cmd_map: true,
+            timeout: None,
+            timeout_pending: false,
+            time: false,
+            time_pending: false,
+            dry_run: false,
+            new_pgroup: false,
+            env: None,
+            skipped: false,
+            func: None,
+            mock_call: None,
             args: vec![],
             vars: HashMap::new(),
             redirects: vec![],
@@ -328,7 +1054,56 @@ impl Cmd {
         }
 
         let arg_str = arg.to_string_lossy().to_string();
-        if arg_str != IGNORE_CMD && !self.args.iter().any(|cmd| *cmd != IGNORE_CMD) {
+        if self.time_pending {
+            self.time_pending = false;
+            if arg_str.starts_with('-') {
+                // followed by options, so run the external `time` command instead
+                self.time = false;
+                self.in_cmd_map = CMD_MAP.lock().unwrap().contains_key(OsStr::new(TIME_CMD));
+                self.args.push(TIME_CMD.into());
+            }
+        }
+        if self.timeout_pending {
+            self.timeout_pending = false;
+            if let Some(timeout) = parse_duration(&arg_str) {
+                // like coreutils, 0 disables the timeout
+                if !timeout.is_zero() {
+                    self.timeout = Some(timeout);
+                }
+                return self;
+            }
+            // not followed by a duration, so run the external `timeout` command instead
+            self.add_timeout_cmd();
+        } else if self.env.is_some()
+            && self.args.iter().all(|cmd| *cmd == IGNORE_CMD)
+            && self.add_env_arg(arg)
+        {
+            return self;
+        } else if arg_str != IGNORE_CMD && !self.args.iter().any(|cmd| *cmd != IGNORE_CMD) {
+            if arg_str == TIMEOUT_CMD && self.timeout.is_none() {
+                self.timeout_pending = true;
+                return self;
+            }
+            if arg_str == TIME_CMD && !self.time {
+                self.time = true;
+                self.time_pending = true;
+                return self;
+            }
+            if arg_str == DRY_RUN_CMD && !self.dry_run {
+                self.dry_run = true;
+                return self;
+            }
+            if arg_str == NEW_PGROUP_CMD && !self.new_pgroup {
+                self.new_pgroup = true;
+                return self;
+            }
+            if arg_str == ENV_CMD && self.env.is_none() {
+                self.env = Some(EnvPrefix {
+                    args: vec![arg.into()],
+                    ..Default::default()
+                });
+                return self;
+            }
             let v: Vec<&str> = arg_str.split('=').collect();
             if v.len() == 2 && v[0].chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
                 self.vars.insert(v[0].into(), v[1].into());
@@ -356,6 +1131,114 @@ impl Cmd {
         self
     }
 
+    // Replaces the command by one which just drains its input, unless it is a builtin without
+    // side effects whose output is not captured
+    fn dry_run(&mut self, captured: bool) {
+        let arg0 = self.arg0();
+        let safe = DRY_RUN_SAFE_CMDS.iter().any(|cmd| arg0 == *cmd);
+        if !safe || captured || !self.redirects.is_empty() {
+            self.skipped = true;
+            self.redirects.clear();
+        }
+    }
+
+    // Exports the session's variables, and runs its custom commands instead of external ones
+    fn apply_shell(&mut self, sh: &Shell) {
+        for (k, v) in sh.vars.iter() {
+            let hidden = self.env.as_ref().is_some_and(|env| env.hides(k));
+            if !hidden && !self.vars.contains_key(k) {
+                self.vars.insert(k.clone(), v.clone());
+                if let Some(cmd) = self.std_cmd.as_mut() {
+                    cmd.env(k, v);
+                }
+            }
+        }
+        // the commands of the session take precedence over builtins and registered commands
+        if let Some(func) = sh.cmds.get(&self.arg0()) {
+            self.func = Some(func.clone());
+            self.std_cmd = None;
+            self.mock_call = None;
+        }
+    }
+
+    // Returns whether the argument belongs to the `env` prefix, running the external `env`
+    // command instead for the options which are not supported
+    fn add_env_arg(&mut self, arg: &OsStr) -> bool {
+        let env = self.env.as_mut().unwrap();
+        let arg_str = arg.to_string_lossy();
+        match env.pending.take() {
+            Some("-u") => {
+                self.vars.remove(arg_str.as_ref());
+                env.unset.push(arg_str.to_string());
+            }
+            Some("-k") => {
+                if let Ok(v) = std::env::var(arg_str.as_ref()) {
+                    env.kept.push(format!("{arg_str}={v}").into());
+                    self.vars.insert(arg_str.to_string(), v);
+                    env.vars.push(arg_str.to_string());
+                }
+                env.options_end = env.args.len();
+                return true;
+            }
+            Some(_) => env.dir = Some(arg.into()),
+            None if arg_str == "-i" => {
+                self.vars.clear();
+                env.clear = true;
+            }
+            None if arg_str == "-k" => {
+                // keeping variables clears the rest of the environment
+                if !env.clear {
+                    self.vars.clear();
+                    env.clear = true;
+                    env.args.push("-i".into());
+                }
+                env.pending = Some("-k");
+                return true;
+            }
+            None if arg_str == "-u" => env.pending = Some("-u"),
+            None if arg_str == "-C" => env.pending = Some("-C"),
+            None if arg_str.starts_with('-') => {
+                self.add_env_cmd();
+                return false;
+            }
+            None => match arg_str.split_once('=') {
+                Some((k, v))
+                    if !k.is_empty()
+                        && k.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+                {
+                    self.vars.insert(k.into(), v.into());
+                    env.vars.push(k.into());
+                    env.args.push(arg.into());
+                    return true;
+                }
+                _ => return false,
+            },
+        }
+        env.args.push(arg.into());
+        env.options_end = env.args.len();
+        true
+    }
+
+    fn add_env_cmd(&mut self) {
+        let mut env = self.env.take().unwrap();
+        for k in env.vars.iter() {
+            self.vars.remove(k);
+        }
+        // the external `env` has no `-k`, so the kept variables are assigned after the options
+        let end = env.options_end;
+        env.args.splice(end..end, env.kept);
+        self.in_cmd_map = CMD_MAP.lock().unwrap().contains_key(OsStr::new(ENV_CMD));
+        self.args.extend(env.args);
+    }
+
+    fn add_timeout_cmd(&mut self) {
+        self.in_cmd_map = CMD_MAP
+            .lock()
+            .unwrap()
+            .contains_key(OsStr::new(TIMEOUT_CMD));
+        self.args.push(TIMEOUT_CMD.into());
+    }
+
     fn arg0(&self) -> OsString {
         let mut args = self.args.iter().skip_while(|cmd| *cmd == IGNORE_CMD);
         if let Some(arg) = args.next() {
@@ -375,15 +1258,41 @@ impl Cmd {
     }
 
     fn gen_command(mut self) -> (bool, Self) {
+        if self.timeout_pending {
+            self.timeout_pending = false;
+            self.add_timeout_cmd();
+        }
+        if let Some(env) = self.env.as_ref() {
+            if env.pending.is_some() || self.args.iter().all(|cmd| *cmd == IGNORE_CMD) {
+                // no command to run, so run the external `env` command instead
+                self.add_env_cmd();
+            }
+        }
         let args: Vec<OsString> = self
             .args
             .iter()
             .skip_while(|cmd| *cmd == IGNORE_CMD)
             .map(|s| s.into())
             .collect();
+        // the options which the `tee` builtin doesn't support are left to the external `tee`
+        if self.in_cmd_map && args.first().is_some_and(|arg| arg == TEE_CMD) {
+            self.in_cmd_map = tee_supports(&args[1..]);
+        }
         if !self.in_cmd_map {
+            self.mock_call = mock::find(&args);
+            self.func = self.mock_call.as_ref().and_then(MockCall::handler);
+        }
+        if !self.in_cmd_map && self.func.is_none() {
             let mut cmd = Command::new(&args[0]);
             cmd.args(&args[1..]);
+            if let Some(env) = self.env.as_ref() {
+                if env.clear {
+                    cmd.env_clear();
+                }
+                for k in env.unset.iter() {
+                    cmd.env_remove(k);
+                }
+            }
             for (k, v) in self.vars.iter() {
                 cmd.env(k, v);
             }
@@ -392,36 +1301,68 @@ impl Cmd {
         (self.args.len() > args.len(), self)
     }
 
-    fn spawn(mut self, current_dir: &mut PathBuf, with_output: bool) -> Result<CmdChild> {
+    // Sets up everything needed to start the command, except for `cd`, `pushd` and `popd`,
+    // which are run right away
+    pub(crate) fn prepare(
+        mut self,
+        dirs: &mut WorkDirs,
+        pgroup: Option<u32>,
+    ) -> Result<PreparedCmd> {
         let arg0 = self.arg0();
-        if arg0 == CD_CMD {
-            self.run_cd_cmd(current_dir, &self.file, self.line)?;
-            Ok(CmdChild::new(
-                CmdChildHandle::SyncFn,
-                self.cmd_str(),
-                self.file,
-                self.line,
-                self.stdout_logging,
-                self.stderr_logging,
-            ))
-        } else if self.in_cmd_map {
-            let cmd_str = self.cmd_str();
-            let pipe_out = self.stdout_logging.is_none();
-            let mut env = CmdEnv {
-                args: self
-                    .args
+        let cmd_str = self.cmd_str();
+        if arg0 == DIRS_CMD && !self.in_cmd_map && !self.skipped && self.func.is_none() {
+            self.std_cmd = None;
+            self.func = Some(dirs_cmd(dirs));
+        }
+        // `env -C` runs the command in another directory, relative to the working one
+        let current_dir = match self.env.as_ref().and_then(|env| env.dir.as_ref()) {
+            Some(dir) => dirs.current.join(dir),
+            None => dirs.current.clone(),
+        };
+        if let Some(cmd) = self.std_cmd.as_mut() {
+            // setup current_dir
+            if !current_dir.as_os_str().is_empty() {
+                cmd.current_dir(&current_dir);
+            }
+
+            // setup process group, 0 to start a new one
+            if let Some(pgid) = pgroup {
+                cmd.process_group(pgid as i32);
+            }
+        }
+        if !self.skipped {
+            if let Some(call) = self.mock_call.take() {
+                call.record();
+            }
+            let inherit_stdin = self.stdin_redirect.is_none();
+            if let Some(func) = transcript::hook(&mut self.std_cmd, inherit_stdin)? {
+                self.func = Some(func);
+            }
+        }
+        let task = if [CD_CMD, PUSHD_CMD, POPD_CMD].iter().any(|cmd| arg0 == *cmd) && !self.skipped
+        {
+            self.run_dir_cmd(&arg0, dirs)?;
+            CmdTask::Done
+        } else if self.in_cmd_map || self.skipped || self.func.is_some() {
+            let env = CmdEnv {
+                args: std::mem::take(&mut self.args)
                     .into_iter()
                     .skip_while(|cmd| *cmd == IGNORE_CMD)
                     .map(|s| s.to_string_lossy().to_string())
                     .collect(),
-                vars: self.vars,
+                vars: std::mem::take(&mut self.vars),
                 current_dir: if current_dir.as_os_str().is_empty() {
                     std::env::current_dir()?
+                } else if current_dir.is_relative() {
+                    std::env::current_dir()?.join(current_dir)
                 } else {
-                    current_dir.clone()
+                    current_dir
                 },
                 stdin: if let Some(redirect_in) = self.stdin_redirect.take() {
                     redirect_in
+                } else if self.skipped {
+                    // there is nothing to drain, without a previous command
+                    CmdIn::null()
                 } else {
                     CmdIn::pipe(os_pipe::dup_stdin()?)
                 },
@@ -435,38 +1376,23 @@ impl Cmd {
                 } else {
                     CmdOut::pipe(os_pipe::dup_stderr()?)
                 },
+                cancel: CancelToken::default(),
             };
-
-            let internal_cmd = CMD_MAP.lock().unwrap()[&arg0];
-            if pipe_out || with_output {
-                let handle = thread::Builder::new().spawn(move || internal_cmd(&mut env))?;
-                Ok(CmdChild::new(
-                    CmdChildHandle::Thread(handle),
-                    cmd_str,
-                    self.file,
-                    self.line,
-                    self.stdout_logging,
-                    self.stderr_logging,
-                ))
+            let func: CmdFn = if self.skipped {
+                Arc::new(builtin_dry_run)
+            } else if let Some(func) = self.func.take() {
+                func
             } else {
-                internal_cmd(&mut env)?;
-                Ok(CmdChild::new(
-                    CmdChildHandle::SyncFn,
-                    cmd_str,
-                    self.file,
-                    self.line,
-                    self.stdout_logging,
-                    self.stderr_logging,
-                ))
-            }
+                let func = CMD_MAP.lock().unwrap().get(&arg0).cloned();
+                func.ok_or_else(|| {
+                    let err_msg = format!("{arg0:?}: custom command not registered");
+                    Error::new(ErrorKind::NotFound, err_msg)
+                })?
+            };
+            CmdTask::Builtin(func, env)
         } else {
             let mut cmd = self.std_cmd.take().unwrap();
 
-            // setup current_dir
-            if !current_dir.as_os_str().is_empty() {
-                cmd.current_dir(current_dir.clone());
-            }
-
             // update stdin
             if let Some(redirect_in) = self.stdin_redirect.take() {
                 cmd.stdin(redirect_in);
@@ -481,39 +1407,60 @@ impl Cmd {
             if let Some(redirect_err) = self.stderr_redirect.take() {
                 cmd.stderr(redirect_err);
             }
+            CmdTask::Proc(cmd)
+        };
+        Ok(PreparedCmd {
+            task,
+            cmd_str,
+            name: arg0.to_string_lossy().to_string(),
+            file: self.file,
+            line: self.line,
+            stdout: self.stdout_logging,
+            stderr: self.stderr_logging,
+        })
+    }
 
-            // spawning process
-            let child = cmd.spawn()?;
-            Ok(CmdChild::new(
-                CmdChildHandle::Proc(child),
-                self.cmd_str(),
-                self.file,
-                self.line,
-                self.stdout_logging,
-                self.stderr_logging,
-            ))
+    fn run_dir_cmd(&self, arg0: &OsStr, dirs: &mut WorkDirs) -> CmdResult {
+        let name = arg0.to_string_lossy();
+        let (file, line) = (&self.file, self.line);
+        let args: Vec<_> = self
+            .args
+            .iter()
+            .skip_while(|cmd| *cmd == IGNORE_CMD)
+            .skip(1)
+            .collect();
+        if arg0 == POPD_CMD {
+            if !args.is_empty() {
+                let err_msg = format!("{name}: too many arguments at {file}:{line}");
+                return Err(Error::other(err_msg));
+            }
+            let Some(dir) = dirs.stack.pop() else {
+                let err_msg = format!("{name}: directory stack empty at {file}:{line}");
+                return Err(Error::other(err_msg));
+            };
+            dirs.current = dir;
+            return Ok(());
         }
-    }
 
-    fn run_cd_cmd(&self, current_dir: &mut PathBuf, file: &str, line: u32) -> CmdResult {
-        if self.args.len() == 1 {
-            return Err(Error::new(
-                ErrorKind::Other,
-                "{CD_CMD}: missing directory at {file}:{line}",
-            ));
-        } else if self.args.len() > 2 {
-            let err_msg = format!("{CD_CMD}: too many arguments at {file}:{line}");
-            return Err(Error::new(ErrorKind::Other, err_msg));
+        if args.is_empty() {
+            let err_msg = format!("{name}: missing directory at {file}:{line}");
+            return Err(Error::other(err_msg));
+        } else if args.len() > 1 {
+            let err_msg = format!("{name}: too many arguments at {file}:{line}");
+            return Err(Error::other(err_msg));
         }
 
-        let dir = current_dir.join(&self.args[1]);
+        let dir = dirs.current.join(args[0]);
         if !dir.is_dir() {
-            let err_msg = format!("{CD_CMD}: No such file or directory at {file}:{line}");
-            return Err(Error::new(ErrorKind::Other, err_msg));
+            let err_msg = format!("{name}: No such file or directory at {file}:{line}");
+            return Err(Error::other(err_msg));
         }
 
         dir.access(AccessMode::EXECUTE)?;
-        *current_dir = dir;
+        let prev = std::mem::replace(&mut dirs.current, dir);
+        if arg0 == PUSHD_CMD {
+            dirs.stack.push(prev);
+        }
         Ok(())
     }
 
@@ -643,6 +1590,42 @@ impl fmt::Display for CmdString {
     }
 }
 
+// Parses durations like "30", "1.5s", "500ms", "2m", "1h" or "1d", seconds by default
+fn parse_duration(s: &str) -> Option<Duration> {
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(pos) => s.split_at(pos),
+        None => (s, "s"),
+    };
+    let secs_per_unit = match unit {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        "d" => 86400.0,
+        _ => return None,
+    };
+    let num: f64 = num.parse().ok()?;
+    Duration::try_from_secs_f64(num * secs_per_unit).ok()
+}
+
+fn working_dir(current_dir: &Path) -> PathBuf {
+    if current_dir.as_os_str().is_empty() {
+        std::env::current_dir().unwrap_or_default()
+    } else {
+        current_dir.to_path_buf()
+    }
+}
+
+// `dirs` prints the working directory followed by the stack, the most recent first
+fn dirs_cmd(dirs: &WorkDirs) -> CmdFn {
+    let list = std::iter::once(&dirs.current)
+        .chain(dirs.stack.iter().rev())
+        .map(|dir| working_dir(dir).display().to_string())
+        .collect::<Vec<_>>()
+        .join(" ");
+    Arc::new(move |env| writeln!(env.stdout(), "{list}"))
+}
+
 pub(crate) fn new_cmd_io_error(e: &Error, command: &str, file: &str, line: u32) -> Error {
     Error::new(
         e.kind(),
@@ -656,21 +1639,21 @@ mod tests {
 
     #[test]
     fn test_run_piped_cmds() {
-        let mut current_dir = PathBuf::new();
+        let mut dirs = WorkDirs::default();
         assert!(Cmds::default()
             .pipe(Cmd::default().add_args(["echo", "rust"]))
             .pipe(Cmd::default().add_args(["wc"]))
-            .run_cmd(&mut current_dir)
+            .run_cmd(&mut dirs)
             .is_ok());
     }
 
     #[test]
     fn test_run_piped_funs() {
-        let mut current_dir = PathBuf::new();
+        let mut dirs = WorkDirs::default();
         assert_eq!(
             Cmds::default()
                 .pipe(Cmd::default().add_args(["echo", "rust"]))
-                .run_fun(&mut current_dir)
+                .run_fun(&mut dirs)
                 .unwrap(),
             "rust"
         );
@@ -679,37 +1662,40 @@ mod tests {
             Cmds::default()
                 .pipe(Cmd::default().add_args(["echo", "rust"]))
                 .pipe(Cmd::default().add_args(["wc", "-c"]))
-                .run_fun(&mut current_dir)
+                .run_fun(&mut dirs)
                 .unwrap()
                 .trim(),
             "5"
         );
     }
 
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("-s"), None);
+        assert_eq!(parse_duration("10x"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
     #[test]
     fn test_stdout_redirect() {
-        let mut current_dir = PathBuf::new();
+        let mut dirs = WorkDirs::default();
         let tmp_file = "/tmp/file_echo_rust";
         let mut write_cmd = Cmd::default().add_args(["echo", "rust"]);
         write_cmd = write_cmd.add_redirect(Redirect::StdoutToFile(PathBuf::from(tmp_file), false));
-        assert!(Cmds::default()
-            .pipe(write_cmd)
-            .run_cmd(&mut current_dir)
-            .is_ok());
+        assert!(Cmds::default().pipe(write_cmd).run_cmd(&mut dirs).is_ok());
 
         let read_cmd = Cmd::default().add_args(["cat", tmp_file]);
         assert_eq!(
-            Cmds::default()
-                .pipe(read_cmd)
-                .run_fun(&mut current_dir)
-                .unwrap(),
+            Cmds::default().pipe(read_cmd).run_fun(&mut dirs).unwrap(),
             "rust"
         );
 
         let cleanup_cmd = Cmd::default().add_args(["rm", tmp_file]);
-        assert!(Cmds::default()
-            .pipe(cleanup_cmd)
-            .run_cmd(&mut current_dir)
-            .is_ok());
+        assert!(Cmds::default().pipe(cleanup_cmd).run_cmd(&mut dirs).is_ok());
     }
 }
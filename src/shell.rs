@@ -0,0 +1,158 @@
+use crate::process::{scoped_dir, CmdFn, GroupCmds, WorkDirs};
+#[cfg(feature = "async")]
+use crate::{AsyncCmdChildren, AsyncFunChildren};
+use crate::{CmdChildren, CmdEnv, CmdResult, FunChildren, FunResult, Xtrace};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A shell session, keeping its working directory, environment variables, options and custom
+/// commands across macro invocations.
+///
+/// Commands run in a session with the `in <shell>;` prefix, like `run_cmd!(in sh; ...)`, which
+/// works with all the macros running commands. Several sessions can be used side by side, and
+/// the global settings, like [`set_pipefail`](crate::set_pipefail), still apply to the
+/// commands which are not overridden by the session.
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// let mut sh = Shell::new();
+/// sh.set_var("RUST_LOG", "debug");
+/// run_cmd!(in sh; cd /tmp; mkdir -p build)?;
+/// // still in /tmp, with RUST_LOG set
+/// run_cmd!(in sh; cd build; cargo build)?;
+/// assert_eq!(sh.current_dir(), std::path::Path::new("/tmp/build"));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Clone, Default)]
+pub struct Shell {
+    // empty for the current directory of the process
+    pub(crate) current_dir: PathBuf,
+    pub(crate) dir_stack: Vec<PathBuf>,
+    pub(crate) vars: HashMap<String, String>,
+    pub(crate) pipefail: Option<bool>,
+    pub(crate) dry_run: bool,
+    pub(crate) xtrace: Option<Xtrace>,
+    pub(crate) cmds: HashMap<OsString, CmdFn>,
+}
+
+impl Shell {
+    /// Creates a session starting in the current directory of the process.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the working directory of the session, which `cd`, `pushd` and `popd` change.
+    ///
+    /// It is empty until the first change, for the current directory of the process or of the
+    /// enclosing [`with_dir`](crate::with_dir) scope.
+    pub fn current_dir(&self) -> &Path {
+        &self.current_dir
+    }
+
+    /// Returns the value of an environment variable exported by the session.
+    pub fn var(&self, key: &str) -> Option<&str> {
+        self.vars.get(key).map(String::as_str)
+    }
+
+    /// Exports an environment variable to all the commands of the session, unless they set it
+    /// themselves.
+    pub fn set_var(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.vars.insert(key.into(), value.into());
+    }
+
+    /// Stops exporting an environment variable.
+    pub fn remove_var(&mut self, key: &str) {
+        self.vars.remove(key);
+    }
+
+    /// Sets pipefail or not for the session, overriding [`set_pipefail`](crate::set_pipefail).
+    pub fn set_pipefail(&mut self, enable: bool) {
+        self.pipefail = Some(enable);
+    }
+
+    /// Sets dry-run mode or not for the session, see [`set_dry_run`](crate::set_dry_run).
+    pub fn set_dry_run(&mut self, enable: bool) {
+        self.dry_run = enable;
+    }
+
+    /// Sets where the commands of the session are traced, overriding
+    /// [`set_xtrace`](crate::set_xtrace).
+    pub fn set_xtrace(&mut self, xtrace: Xtrace) {
+        self.xtrace = Some(xtrace);
+    }
+
+    /// Registers a custom command for the session only, taking precedence over the builtins,
+    /// the custom commands registered globally and the programs in `PATH`.
+    pub fn register_cmd<F>(&mut self, name: &str, func: F)
+    where
+        F: Fn(&mut CmdEnv) -> CmdResult + Send + Sync + 'static,
+    {
+        self.cmds.insert(name.into(), Arc::new(func));
+    }
+
+    // Keeps the directories changed by the commands, but not the one of a `with_dir` scope
+    fn leave(&mut self, dirs: WorkDirs) {
+        if !self.current_dir.as_os_str().is_empty() || dirs.current != scoped_dir() {
+            self.current_dir = dirs.current;
+        }
+        self.dir_stack = dirs.stack;
+    }
+
+    #[doc(hidden)]
+    pub fn run_cmd(&mut self, cmds: GroupCmds) -> CmdResult {
+        let mut cmds = cmds.in_shell(self);
+        let res = cmds.run_cmd();
+        self.leave(cmds.into_dirs());
+        res
+    }
+
+    #[doc(hidden)]
+    pub fn run_fun(&mut self, cmds: GroupCmds) -> FunResult {
+        let mut cmds = cmds.in_shell(self);
+        let res = cmds.run_fun();
+        self.leave(cmds.into_dirs());
+        res
+    }
+
+    #[doc(hidden)]
+    pub fn spawn(&mut self, cmds: GroupCmds, with_output: bool) -> Result<CmdChildren> {
+        cmds.in_shell(self).spawn(with_output)
+    }
+
+    #[doc(hidden)]
+    pub fn spawn_with_output(&mut self, cmds: GroupCmds) -> Result<FunChildren> {
+        cmds.in_shell(self).spawn_with_output()
+    }
+}
+
+#[cfg(feature = "async")]
+impl Shell {
+    #[doc(hidden)]
+    pub async fn run_cmd_async(&mut self, cmds: GroupCmds) -> CmdResult {
+        let mut cmds = cmds.in_shell(self);
+        let res = cmds.exec_cmd_async().await;
+        self.leave(cmds.into_dirs());
+        res
+    }
+
+    #[doc(hidden)]
+    pub async fn run_fun_async(&mut self, cmds: GroupCmds) -> FunResult {
+        let mut cmds = cmds.in_shell(self);
+        let res = cmds.exec_fun_async().await;
+        self.leave(cmds.into_dirs());
+        res
+    }
+
+    #[doc(hidden)]
+    pub fn spawn_async(&mut self, cmds: GroupCmds, with_output: bool) -> Result<AsyncCmdChildren> {
+        cmds.in_shell(self).spawn_async(with_output)
+    }
+
+    #[doc(hidden)]
+    pub fn spawn_with_output_async(&mut self, cmds: GroupCmds) -> Result<AsyncFunChildren> {
+        cmds.in_shell(self).spawn_with_output_async()
+    }
+}
@@ -0,0 +1,574 @@
+use crate::io::{CmdIn, CmdOut};
+use crate::process::CmdFn;
+use crate::warn;
+use crate::{CmdEnv, CmdResult};
+use std::cell::RefCell;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// How often a recorded command is checked for exit or cancellation
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+thread_local! {
+    static ACTIVE: RefCell<Option<(usize, Mode)>> = const { RefCell::new(None) };
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone)]
+enum Mode {
+    Record(Arc<Mutex<Recording>>),
+    Replay(Arc<Mutex<Replaying>>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Exit(i32),
+    Signal(i32),
+}
+
+impl Status {
+    fn from_exit_status(status: ExitStatus) -> Self {
+        match status.code() {
+            Some(code) => Status::Exit(code),
+            None => Status::Signal(status.signal().unwrap_or_default()),
+        }
+    }
+
+    fn to_result(self) -> CmdResult {
+        match self {
+            Status::Exit(0) => Ok(()),
+            Status::Exit(code) => Err(Error::other(format!(
+                "exited with error; status code: {code}"
+            ))),
+            Status::Signal(signal) => Err(Error::other(format!(
+                "exited with error; terminated by signal: {signal}"
+            ))),
+        }
+    }
+}
+
+// One external command, with everything it read and wrote
+#[derive(Clone)]
+struct Entry {
+    args: Vec<String>,
+    // not recorded when inherited from the parent process
+    stdin: Option<Vec<u8>>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    // still running while none
+    status: Option<Status>,
+}
+
+impl Entry {
+    fn new(args: Vec<String>) -> Self {
+        Self {
+            args,
+            stdin: None,
+            stdout: vec![],
+            stderr: vec![],
+            status: None,
+        }
+    }
+}
+
+struct Recording {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+impl Recording {
+    // Rewrites the whole transcript, leaving out the commands still running
+    fn save(&self) -> Result<()> {
+        let mut data = vec![];
+        for entry in self.entries.iter().filter(|entry| entry.status.is_some()) {
+            write_entry(&mut data, entry);
+        }
+        std::fs::write(&self.path, data).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("Writing transcript {} failed: {e}", self.path.display()),
+            )
+        })
+    }
+}
+
+struct Replaying {
+    path: PathBuf,
+    entries: Vec<Entry>,
+    next: usize,
+}
+
+impl Replaying {
+    fn next(&mut self, args: &[String]) -> Result<Entry> {
+        let Some(entry) = self.entries.get(self.next) else {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "command [{}] was not recorded in {}, which has only {} commands",
+                    join_args(args),
+                    self.path.display(),
+                    self.entries.len()
+                ),
+            ));
+        };
+        if entry.args != args {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "command [{}] differs from command #{} [{}] recorded in {}",
+                    join_args(args),
+                    self.next + 1,
+                    join_args(&entry.args),
+                    self.path.display()
+                ),
+            ));
+        }
+        self.next += 1;
+        Ok(entry.clone())
+    }
+}
+
+/// Transcript of the external commands started from the current thread, for golden tests.
+///
+/// In record mode, every external command still runs for real, and its arguments, stdin,
+/// stdout, stderr and exit status are saved to a human-readable file. In replay mode, the
+/// commands are not run at all: each one must match the next recorded command, or it fails to
+/// spawn, and gets the recorded output and status instead. So scripts can be tested in CI
+/// without the tools they use installed.
+///
+/// Builtin, custom and mocked commands are neither recorded nor replayed, and neither are
+/// the commands started from other threads. The stdin of a command is only recorded when it
+/// is piped or redirected, not when it is inherited from the current process.
+///
+/// ```no_run
+/// # use cmd_lib::*;
+/// let transcript = if std::env::var("RECORD").is_ok() {
+///     Transcript::record("tests/golden/deploy.txt")?
+/// } else {
+///     Transcript::replay("tests/golden/deploy.txt")?
+/// };
+/// run_cmd!(kubectl apply -f deploy.yaml)?;
+/// let pods = run_fun!(kubectl get pods | grep -c Running)?;
+/// assert_eq!(pods, "3");
+/// transcript.finish()?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Transcript {
+    id: usize,
+    mode: Mode,
+    finished: bool,
+}
+
+impl Transcript {
+    /// Starts recording the commands of the current thread to `path`, which is overwritten.
+    pub fn record(path: impl AsRef<Path>) -> Result<Self> {
+        let recording = Recording {
+            path: path.as_ref().to_path_buf(),
+            entries: vec![],
+        };
+        recording.save()?;
+        Self::start(Mode::Record(Arc::new(Mutex::new(recording))))
+    }
+
+    /// Starts replaying the commands recorded in `path`, instead of running them.
+    pub fn replay(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let data = std::fs::read(&path).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("Reading transcript {} failed: {e}", path.display()),
+            )
+        })?;
+        let entries = Parser::new(&data).entries().map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Reading transcript {} failed: {e}", path.display()),
+            )
+        })?;
+        Self::start(Mode::Replay(Arc::new(Mutex::new(Replaying {
+            path,
+            entries,
+            next: 0,
+        }))))
+    }
+
+    fn start(mode: Mode) -> Result<Self> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        ACTIVE.with(|active| {
+            let mut active = active.borrow_mut();
+            if active.is_some() {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    "a transcript is already active on this thread",
+                ));
+            }
+            *active = Some((id, mode.clone()));
+            Ok(())
+        })?;
+        Ok(Self {
+            id,
+            mode,
+            finished: false,
+        })
+    }
+
+    /// Stops recording or replaying.
+    ///
+    /// It fails if recorded commands are still running, or if some of the replayed ones were
+    /// not run.
+    pub fn finish(mut self) -> CmdResult {
+        self.finished = true;
+        self.check()
+    }
+
+    fn check(&self) -> CmdResult {
+        match &self.mode {
+            Mode::Record(recording) => {
+                let recording = recording.lock().unwrap();
+                let running = recording
+                    .entries
+                    .iter()
+                    .filter(|entry| entry.status.is_none())
+                    .count();
+                if running > 0 {
+                    return Err(Error::other(format!(
+                        "{running} commands recorded in {} are still running",
+                        recording.path.display()
+                    )));
+                }
+            }
+            Mode::Replay(replaying) => {
+                let replaying = replaying.lock().unwrap();
+                if let Some(entry) = replaying.entries.get(replaying.next) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "{} commands recorded in {} were not run, starting with #{} [{}]",
+                            replaying.entries.len() - replaying.next,
+                            replaying.path.display(),
+                            replaying.next + 1,
+                            join_args(&entry.args)
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Transcript {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self.check() {
+                warn!("{e}");
+            }
+        }
+        // the thread local may be gone already, if the transcript is dropped while the thread
+        // exits
+        let _ = ACTIVE.try_with(|active| {
+            let mut active = active.borrow_mut();
+            if matches!(*active, Some((id, _)) if id == self.id) {
+                *active = None;
+            }
+        });
+    }
+}
+
+// Takes over the external command when a transcript is active, returning what to run instead
+pub(crate) fn hook(cmd: &mut Option<Command>, inherit_stdin: bool) -> Result<Option<CmdFn>> {
+    let Some(mode) = ACTIVE.with(|active| active.borrow().as_ref().map(|(_, mode)| mode.clone()))
+    else {
+        return Ok(None);
+    };
+    let Some(std_cmd) = cmd.as_ref() else {
+        return Ok(None);
+    };
+    let args: Vec<String> = std::iter::once(std_cmd.get_program())
+        .chain(std_cmd.get_args())
+        .map(|arg| arg.to_string_lossy().to_string())
+        .collect();
+    match mode {
+        Mode::Record(recording) => {
+            let index = {
+                let mut recording = recording.lock().unwrap();
+                recording.entries.push(Entry::new(args));
+                recording.entries.len() - 1
+            };
+            let std_cmd = Mutex::new(cmd.take());
+            Ok(Some(Arc::new(move |env| {
+                let Some(std_cmd) = std_cmd.lock().unwrap().take() else {
+                    return Err(Error::other("recorded command already run"));
+                };
+                let (entry, status) = record(std_cmd, env, inherit_stdin)?;
+                let mut recording = recording.lock().unwrap();
+                recording.entries[index] = Entry {
+                    args: std::mem::take(&mut recording.entries[index].args),
+                    ..entry
+                };
+                recording.save()?;
+                status.to_result()
+            })))
+        }
+        Mode::Replay(replaying) => {
+            let mut replaying = replaying.lock().unwrap();
+            let entry = replaying.next(&args)?;
+            let path = replaying.path.clone();
+            cmd.take();
+            Ok(Some(Arc::new(move |env| replay(&entry, &path, env))))
+        }
+    }
+}
+
+// Runs the command for real, copying its output while passing it through
+fn record(mut cmd: Command, env: &mut CmdEnv, inherit_stdin: bool) -> Result<(Entry, Status)> {
+    cmd.stdin(if inherit_stdin {
+        Stdio::inherit()
+    } else {
+        Stdio::piped()
+    })
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped());
+    let mut child = cmd.spawn()?;
+    let child_stdin = child.stdin.take();
+    let child_stdout = child.stdout.take().unwrap();
+    let child_stderr = child.stderr.take().unwrap();
+    let (stdin, stdout, stderr, cancel) = env.streams();
+    let mut entry = Entry::new(vec![]);
+    let status = thread::scope(|s| -> Result<Status> {
+        let stdin_pump = child_stdin.map(|child_stdin| s.spawn(|| pump_in(stdin, child_stdin)));
+        let stdout_pump = s.spawn(|| pump_out(child_stdout, stdout));
+        let stderr_pump = s.spawn(|| pump_out(child_stderr, stderr));
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if cancel.is_cancelled() {
+                let _ = child.kill();
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+        entry.stdin = stdin_pump.map(|pump| pump.join().unwrap());
+        entry.stdout = stdout_pump.join().unwrap();
+        entry.stderr = stderr_pump.join().unwrap();
+        Ok(Status::from_exit_status(status))
+    })?;
+    entry.status = Some(status);
+    Ok((entry, status))
+}
+
+// Copies the input into the command until it ends, or until the command stops reading
+fn pump_in(from: &mut CmdIn, mut to: impl Write) -> Vec<u8> {
+    let mut data = vec![];
+    let mut buf = [0; 8192];
+    while let Ok(n) = from.read(&mut buf) {
+        if n == 0 || to.write_all(&buf[..n]).is_err() {
+            break;
+        }
+        data.extend_from_slice(&buf[..n]);
+    }
+    data
+}
+
+// Copies the command output until it ends, or until nothing reads it any more
+fn pump_out(mut from: impl Read, to: &mut CmdOut) -> Vec<u8> {
+    let mut data = vec![];
+    let mut buf = [0; 8192];
+    while let Ok(n) = from.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..n]);
+        if to.write_all(&buf[..n]).is_err() {
+            break;
+        }
+    }
+    data
+}
+
+fn replay(entry: &Entry, path: &Path, env: &mut CmdEnv) -> CmdResult {
+    if let Some(expected) = &entry.stdin {
+        let mut stdin = vec![];
+        env.stdin().read_to_end(&mut stdin)?;
+        if stdin != *expected {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "stdin of [{}] differs from the one recorded in {}",
+                    join_args(&entry.args),
+                    path.display()
+                ),
+            ));
+        }
+    }
+    env.stdout().write_all(&entry.stdout)?;
+    env.stderr().write_all(&entry.stderr)?;
+    entry.status.unwrap_or(Status::Exit(0)).to_result()
+}
+
+fn join_args(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Quotes an argument like a shell would need it, only when it has to
+fn quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "%+,-./:=@_".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+// The format of an entry is:
+//
+//     $ cmd arg1 'arg 2'
+//     stdin 6
+//     hello
+//
+//     stdout 6
+//     HELLO
+//
+//     exit 0
+//
+// where the data blocks are prefixed with their length in bytes, and left out when empty,
+// except for stdin, which is left out when it was not recorded.
+fn write_entry(out: &mut Vec<u8>, entry: &Entry) {
+    writeln!(out, "$ {}", join_args(&entry.args)).unwrap();
+    let mut write_block = |name: &str, data: &[u8]| {
+        writeln!(out, "{name} {}", data.len()).unwrap();
+        out.extend_from_slice(data);
+        out.push(b'\n');
+    };
+    if let Some(stdin) = &entry.stdin {
+        write_block("stdin", stdin);
+    }
+    if !entry.stdout.is_empty() {
+        write_block("stdout", &entry.stdout);
+    }
+    if !entry.stderr.is_empty() {
+        write_block("stderr", &entry.stderr);
+    }
+    match entry.status.unwrap_or(Status::Exit(0)) {
+        Status::Exit(code) => writeln!(out, "exit {code}\n").unwrap(),
+        Status::Signal(signal) => writeln!(out, "signal {signal}\n").unwrap(),
+    }
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn entries(&mut self) -> std::result::Result<Vec<Entry>, String> {
+        let mut entries = vec![];
+        loop {
+            while self.data.get(self.pos) == Some(&b'\n') {
+                self.pos += 1;
+            }
+            if self.pos == self.data.len() {
+                return Ok(entries);
+            }
+            if !self.data[self.pos..].starts_with(b"$ ") {
+                return Err(self.error("expected a command starting with \"$ \""));
+            }
+            self.pos += 2;
+            let mut entry = Entry::new(self.args()?);
+            while entry.status.is_none() {
+                let line = self.line();
+                let (name, value) = line.split_once(' ').unwrap_or((&line, ""));
+                match name {
+                    "stdin" => entry.stdin = Some(self.block(value)?),
+                    "stdout" => entry.stdout = self.block(value)?,
+                    "stderr" => entry.stderr = self.block(value)?,
+                    "exit" => entry.status = Some(Status::Exit(self.number(value)?)),
+                    "signal" => entry.status = Some(Status::Signal(self.number(value)?)),
+                    _ => return Err(self.error(&format!("unexpected line {line:?}"))),
+                }
+            }
+            entries.push(entry);
+        }
+    }
+
+    // Splits the rest of the line like a shell would, with single quotes and backslashes
+    fn args(&mut self) -> std::result::Result<Vec<String>, String> {
+        let mut args = vec![];
+        let mut arg: Option<Vec<u8>> = None;
+        loop {
+            let Some(&c) = self.data.get(self.pos) else {
+                return Err(self.error("unexpected end of file"));
+            };
+            self.pos += 1;
+            match c {
+                b'\n' | b' ' => {
+                    if let Some(arg) = arg.take() {
+                        args.push(String::from_utf8_lossy(&arg).to_string());
+                    }
+                    if c == b'\n' {
+                        return Ok(args);
+                    }
+                }
+                b'\'' => {
+                    let Some(len) = self.data[self.pos..].iter().position(|&c| c == b'\'') else {
+                        return Err(self.error("unterminated quote"));
+                    };
+                    let arg = arg.get_or_insert_with(Vec::new);
+                    arg.extend_from_slice(&self.data[self.pos..self.pos + len]);
+                    self.pos += len + 1;
+                }
+                b'\\' if self.pos < self.data.len() => {
+                    arg.get_or_insert_with(Vec::new).push(self.data[self.pos]);
+                    self.pos += 1;
+                }
+                _ => arg.get_or_insert_with(Vec::new).push(c),
+            }
+        }
+    }
+
+    fn line(&mut self) -> String {
+        let rest = &self.data[self.pos..];
+        let len = rest.iter().position(|&c| c == b'\n').unwrap_or(rest.len());
+        self.pos += (len + 1).min(rest.len());
+        String::from_utf8_lossy(&rest[..len]).to_string()
+    }
+
+    fn block(&mut self, len: &str) -> std::result::Result<Vec<u8>, String> {
+        let len: usize = self.number(len)?;
+        let end = self.pos + len;
+        if self.data.get(end) != Some(&b'\n') {
+            return Err(self.error(&format!("expected {len} bytes of data")));
+        }
+        let data = self.data[self.pos..end].to_vec();
+        self.pos = end + 1;
+        Ok(data)
+    }
+
+    fn number<T: std::str::FromStr>(&self, value: &str) -> std::result::Result<T, String> {
+        value
+            .parse()
+            .map_err(|_| self.error(&format!("invalid number {value:?}")))
+    }
+
+    fn error(&self, msg: &str) -> String {
+        let line = self.data[..self.pos.min(self.data.len())]
+            .iter()
+            .filter(|&&c| c == b'\n')
+            .count();
+        format!("{msg} near line {}", line + 1)
+    }
+}
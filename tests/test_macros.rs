@@ -255,3 +255,897 @@ fn test_empty_arg() {
     let opt = "";
     assert!(run_cmd!(ls $opt).is_ok());
 }
+
+#[test]
+fn test_stderr_tail_in_error() {
+    let err = spawn!(bash -c r"for i in 1 2 3; do echo line$i >&2; done; exit 1")
+        .unwrap()
+        .stderr_tail(2)
+        .wait()
+        .unwrap_err();
+    let msg = err.to_string();
+    assert!(msg.contains("line2") && msg.contains("line3"));
+    assert!(!msg.contains("line1"));
+
+    let err = spawn_with_output!(bash -c "echo oops >&2; exit 2")
+        .unwrap()
+        .stderr_tail(10)
+        .wait_with_output()
+        .unwrap_err();
+    assert!(err.to_string().contains("oops"));
+}
+
+#[test]
+fn test_timeout() {
+    use std::io::ErrorKind;
+    use std::time::{Duration, Instant};
+
+    let now = Instant::now();
+    let err = run_cmd!(timeout 100ms sleep 10).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert!(now.elapsed() < Duration::from_secs(5));
+    assert!(run_cmd!(timeout 10s echo ok).is_ok());
+    assert_eq!(run_fun!(timeout 10 echo ok).unwrap(), "ok");
+    assert!(run_cmd!(timeout 0 sleep 0.2).is_ok());
+
+    let err = spawn!(sleep 10 | cat)
+        .unwrap()
+        .wait_timeout(Duration::from_millis(100))
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert_eq!(
+        spawn_with_output!(echo hi)
+            .unwrap()
+            .wait_timeout(Duration::from_secs(10))
+            .unwrap(),
+        "hi"
+    );
+
+    // already exited when the timeout expires
+    let mut proc = spawn!(timeout 100ms true).unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(proc.wait().is_ok());
+
+    // options are for the external `timeout` command
+    assert_eq!(run_fun!(timeout -s KILL 5 echo ok).unwrap(), "ok");
+    assert!(run_cmd!(timeout -s KILL 0.1 sleep 10).is_err());
+}
+
+#[test]
+fn test_try_wait() {
+    use std::time::Duration;
+
+    let mut proc = spawn!(sleep 0.5 | cat).unwrap();
+    assert!(proc.try_wait().is_none());
+    assert_eq!(
+        proc.statuses(),
+        vec![StageStatus::Running, StageStatus::Running]
+    );
+    let res = loop {
+        if let Some(res) = proc.try_wait() {
+            break res;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    assert!(res.is_ok());
+    assert!(proc.try_wait().unwrap().is_ok());
+
+    let mut proc = spawn!(echo xx | false).unwrap();
+    while proc.statuses().contains(&StageStatus::Running) {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(
+        proc.statuses(),
+        vec![StageStatus::Exited(0), StageStatus::Exited(1)]
+    );
+    assert!(proc.try_wait().unwrap().is_err());
+    // polling again after completion returns the same result
+    assert!(proc.try_wait().unwrap().is_err());
+    assert!(proc.wait().is_err());
+}
+
+#[test]
+fn test_signal_process_group() {
+    // spawned children stay in the caller's group by default
+    let mut proc = spawn!(sleep 30).unwrap();
+    let pid = proc.pids()[0];
+    let pgid = run_fun!(ps -o pgid= -p $pid).unwrap();
+    let own_pid = std::process::id();
+    assert_eq!(pgid, run_fun!(ps -o pgid= -p $own_pid).unwrap());
+    proc.kill().unwrap();
+
+    // bash forks `sleep` as a grandchild, which has to be killed with the group
+    let mut proc = spawn!(new_pgroup bash -c "sleep 30; echo done").unwrap();
+    let pgid = proc.pids()[0];
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    assert!(run_cmd!(pgrep -g $pgid sleep > /dev/null).is_ok());
+    proc.terminate().unwrap();
+    // the orphaned `sleep` can be left as a zombie for a while, until init reaps it
+    let gone = (0..100).any(|_| {
+        let stats = run_fun!(ps -e -o pgid=,stat=).unwrap();
+        let alive = stats.lines().any(|stat| {
+            let mut fields = stat.split_whitespace();
+            fields.next() == Some(pgid.to_string().as_str())
+                && !fields.next().unwrap_or_default().starts_with('Z')
+        });
+        if !alive {
+            return true;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        false
+    });
+    assert!(gone);
+
+    let mut proc = spawn!(sleep 30 | cat).unwrap();
+    proc.interrupt().unwrap();
+    assert!(proc.wait().is_err());
+    let mut proc = spawn!(new_pgroup bash -c "sleep 30; echo done").unwrap();
+    proc.signal(Signal::Kill).unwrap();
+    assert!(proc.wait().is_err());
+    // already waited for
+    assert!(proc.terminate().is_ok());
+
+    let mut proc = spawn!(new_pgroup bash -c "trap 'exit 3' TERM; sleep 30 & wait").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    assert!(proc.terminate().is_err());
+    assert!(proc.terminate().is_ok());
+}
+
+#[test]
+fn test_cancel_custom_cmd() {
+    use std::io::{ErrorKind, Read};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    static CANCELLED: AtomicBool = AtomicBool::new(false);
+    fn wait_cancelled(env: &mut CmdEnv) -> CmdResult {
+        while !env.is_cancelled() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        CANCELLED.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+    fn read_stdin(env: &mut CmdEnv) -> CmdResult {
+        let mut buf = vec![];
+        env.stdin().read_to_end(&mut buf)?;
+        Ok(())
+    }
+    use_custom_cmd!(wait_cancelled, read_stdin);
+
+    let mut proc = spawn!(wait_cancelled).unwrap();
+    assert!(proc.kill().is_ok());
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(CANCELLED.load(Ordering::SeqCst));
+
+    // blocked reading from a pipe
+    let err = spawn!(sleep 30 | read_stdin)
+        .unwrap()
+        .wait_timeout(Duration::from_millis(100))
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+}
+
+#[test]
+fn test_kill_on_drop() {
+    use std::io::ErrorKind;
+    use std::time::{Duration, Instant};
+
+    let pids = {
+        let proc = spawn!(sleep 30 | sleep 30).unwrap().kill_on_drop(true);
+        proc.pids()
+    };
+    // dropped children are reaped, so the pids no longer exist
+    for pid in pids {
+        assert!(run_cmd!(kill -0 $pid 2>/dev/null).is_err());
+    }
+
+    let proc = spawn_with_output!(sleep 30).unwrap().kill_on_drop(true);
+    let pid = proc.pids()[0];
+    drop(proc);
+    assert!(run_cmd!(kill -0 $pid 2>/dev/null).is_err());
+
+    // custom commands ignoring their cancellation are left running after the grace period
+    fn ignore_cancel(_env: &mut CmdEnv) -> CmdResult {
+        std::thread::sleep(Duration::from_secs(30));
+        Ok(())
+    }
+    use_custom_cmd!(ignore_cancel);
+    let now = Instant::now();
+    let dropped = std::thread::spawn(|| drop(spawn!(ignore_cancel).unwrap().kill_on_drop(true)));
+    let mut proc = spawn!(ignore_cancel).unwrap();
+    assert_eq!(proc.terminate().unwrap_err().kind(), ErrorKind::TimedOut);
+    dropped.join().unwrap();
+    assert!(now.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn test_pipe_spawn_failure_cleanup() {
+    let err = match spawn!(sleep 3171 | cat | nonexistent_cmd_xyz) {
+        Ok(_) => panic!("spawning should fail"),
+        Err(e) => e,
+    };
+    assert!(err.to_string().contains("failed at stage 3"));
+    // the already started stages were killed and reaped
+    assert!(run_cmd!(pgrep -f "^sleep 3171$" >/dev/null).is_err());
+
+    assert!(run_cmd!(sleep 3172 | nonexistent_cmd_xyz).is_err());
+    assert!(run_cmd!(pgrep -f "^sleep 3172$" >/dev/null).is_err());
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async() {
+    use std::io::ErrorKind;
+
+    assert!(run_cmd_async!(ls /).await.is_ok());
+    assert!(run_cmd_async!(false).await.is_err());
+    assert_eq!(
+        run_fun_async!(echo "hello, rust" | sed r"s/rust/cmd_lib/g")
+            .await
+            .unwrap(),
+        "hello, cmd_lib"
+    );
+
+    let (res, stdout, stderr) = spawn_with_output_async!(bash -c "echo out; echo err >&2")
+        .unwrap()
+        .wait_with_all()
+        .await;
+    assert!(res.is_ok());
+    assert_eq!(stdout, "out");
+    assert_eq!(stderr, "err");
+
+    let mut proc = spawn_async!(timeout 100ms sleep 10).unwrap();
+    assert_eq!(proc.wait().await.unwrap_err().kind(), ErrorKind::TimedOut);
+    assert_eq!(proc.wait().await.unwrap_err().kind(), ErrorKind::TimedOut);
+    let mut proc = spawn_async!(sleep 10).unwrap();
+    proc.kill().unwrap();
+    assert!(proc.wait().await.is_ok());
+
+    // timed out commands get SIGTERM first
+    let file = "/tmp/cmd_lib_test_async_term.txt";
+    run_cmd!(rm -f $file).unwrap();
+    let trap = format!("trap 'touch {file}; exit 3' TERM; while true; do sleep 0.05; done");
+    let res = run_cmd_async!(timeout 100ms bash -c $trap).await;
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::TimedOut);
+    assert!(std::path::Path::new(file).exists());
+    run_cmd!(rm -f $file).unwrap();
+
+    // custom commands run on the blocking thread pool
+    fn upper(env: &mut CmdEnv) -> CmdResult {
+        let mut input = String::new();
+        std::io::Read::read_to_string(env.stdin(), &mut input)?;
+        write!(env.stdout(), "{}", input.to_uppercase())
+    }
+    use std::io::Write;
+    use_custom_cmd!(upper);
+    assert_eq!(run_fun_async!(echo abc | upper).await.unwrap(), "ABC");
+
+    let mut sh = Shell::new();
+    run_cmd_async!(in sh; cd /tmp).await.unwrap();
+    assert_eq!(run_fun_async!(in sh; pwd).await.unwrap(), "/tmp");
+    assert!(run_cmd_async!(in sh; false | true).await.is_err());
+    sh.set_pipefail(false);
+    assert!(run_cmd_async!(in sh; false | true).await.is_ok());
+}
+
+#[test]
+fn test_stdout_lines() {
+    let lines: Vec<String> = spawn_with_output!(seq 1 5)
+        .unwrap()
+        .into_lines()
+        .map(|line| line.unwrap())
+        .collect();
+    assert_eq!(lines, vec!["1", "2", "3", "4", "5"]);
+
+    // the failure is reported as the last item
+    let mut proc = spawn_with_output!(bash -c "echo a; echo b; exit 3").unwrap();
+    let items: Vec<_> = proc.lines().collect();
+    assert_eq!(items.len(), 3);
+    assert_eq!(items[1].as_ref().unwrap(), "b");
+    assert!(items[2].is_err());
+
+    // stopping early
+    let mut lines = spawn_with_output!(seq 1 100000).unwrap().into_lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "1");
+    assert!(lines.finish().is_ok());
+
+    let bytes: Vec<u8> = spawn_with_output!(printf "a\nb")
+        .unwrap()
+        .into_chunks()
+        .flat_map(|chunk| chunk.unwrap())
+        .collect();
+    assert_eq!(bytes, b"a\nb");
+}
+
+#[test]
+fn test_stderr_sink() {
+    use std::sync::{Arc, Mutex};
+
+    let captured = Arc::new(Mutex::new(String::new()));
+    spawn!(bash -c "echo a >&2; echo b >&2")
+        .unwrap()
+        .stderr_sink(StderrSink::Capture(captured.clone()))
+        .wait()
+        .unwrap();
+    assert_eq!(*captured.lock().unwrap(), "a\nb");
+
+    let lines = Arc::new(Mutex::new(vec![]));
+    let sink = {
+        let lines = lines.clone();
+        StderrSink::custom(move |cmd, line| lines.lock().unwrap().push(format!("{cmd}: {line}")))
+    };
+    let output = spawn_with_output!(bash -c "echo out; echo err >&2")
+        .unwrap()
+        .stderr_sink(sink)
+        .wait_with_output()
+        .unwrap();
+    assert_eq!(output, "out");
+    assert_eq!(
+        *lines.lock().unwrap(),
+        vec![r#""bash" "-c" "echo out; echo err >&2": err"#]
+    );
+}
+
+#[test]
+fn test_binary_stderr() {
+    use std::sync::{Arc, Mutex};
+
+    // lines after invalid UTF-8 are still forwarded
+    let captured = Arc::new(Mutex::new(String::new()));
+    let output =
+        spawn_with_output!(bash -c "head -c 200000 /dev/zero | tr '\\0' '\\377' >&2; echo >&2; echo after >&2; echo done")
+            .unwrap()
+            .stderr_sink(StderrSink::Capture(captured.clone()))
+            .wait_with_output()
+            .unwrap();
+    assert_eq!(output, "done");
+    assert!(captured.lock().unwrap().ends_with("\u{fffd}\nafter"));
+
+    let (res, stdout, stderr) =
+        spawn_with_output!(bash -c "printf 'a\\xff\\n\\xfe' >&2; printf '\\xfd'")
+            .unwrap()
+            .wait_with_all_raw();
+    assert!(res.is_ok());
+    assert_eq!(stdout, b"\xfd");
+    assert_eq!(stderr, b"a\xff\n\xfe");
+
+    let (res, _, stderr) = spawn_with_output!(bash -c "printf 'a\\xff\\n' >&2")
+        .unwrap()
+        .wait_with_all();
+    assert!(res.is_ok());
+    assert_eq!(stderr, "a\u{fffd}");
+}
+
+#[test]
+fn test_stderr_prefix() {
+    use std::sync::{Arc, Mutex};
+
+    let captured = Arc::new(Mutex::new(String::new()));
+    spawn!(bash -c "echo a >&2")
+        .unwrap()
+        .stderr_sink(StderrSink::Capture(captured.clone()))
+        .stderr_prefix(StderrPrefix::Cmd)
+        .wait()
+        .unwrap();
+    assert_eq!(*captured.lock().unwrap(), "[bash] a");
+
+    let captured = Arc::new(Mutex::new(String::new()));
+    let mut proc = spawn!(bash -c "echo b >&2")
+        .unwrap()
+        .stderr_sink(StderrSink::Capture(captured.clone()))
+        .stderr_prefix(StderrPrefix::Pid);
+    let pid = proc.pids()[0];
+    proc.wait().unwrap();
+    assert_eq!(*captured.lock().unwrap(), format!("[{pid}] b"));
+
+    let captured = Arc::new(Mutex::new(String::new()));
+    spawn!(bash -c "echo c >&2")
+        .unwrap()
+        .stderr_sink(StderrSink::Capture(captured.clone()))
+        .stderr_prefix(StderrPrefix::Tag("job".into()))
+        .stderr_timestamp(true)
+        .wait()
+        .unwrap();
+    // HH:MM:SS.mmm [job] c
+    let line = captured.lock().unwrap().clone();
+    assert_eq!(line.len(), "00:00:00.000 [job] c".len());
+    assert!(line.ends_with(" [job] c"));
+}
+
+#[test]
+fn test_tee() {
+    let file = "/tmp/cmd_lib_test_tee.txt";
+    assert_eq!(run_fun!(echo hello | tee $file).unwrap(), "hello");
+    assert_eq!(
+        run_fun!(echo world | tee -a $file | wc -l).unwrap().trim(),
+        "1"
+    );
+    assert_eq!(run_fun!(cat $file).unwrap(), "hello\nworld");
+    // options the builtin doesn't support are left to the external `tee`
+    assert_eq!(
+        run_fun!(echo again | tee -i --append $file).unwrap(),
+        "again"
+    );
+    assert_eq!(run_fun!(cat $file).unwrap(), "hello\nworld\nagain");
+    assert!(!std::path::Path::new("-i").exists());
+
+    let output = spawn_with_output!(bash -c "echo a; echo b")
+        .unwrap()
+        .tee(Tee::File(file.into()))
+        .wait_with_output()
+        .unwrap();
+    assert_eq!(output, "a\nb");
+    assert_eq!(std::fs::read_to_string(file).unwrap(), "a\nb\n");
+    run_cmd!(rm -f $file).unwrap();
+}
+
+#[test]
+fn test_wait_with_transcript() {
+    let (res, transcript) =
+        spawn_with_output!(bash -c "echo out1; sleep 0.1; echo err1 >&2; sleep 0.1; echo out2")
+            .unwrap()
+            .wait_with_transcript(true);
+    assert!(res.is_ok());
+    let chunks: Vec<_> = transcript
+        .iter()
+        .map(|chunk| (chunk.stream, chunk.text().into_owned()))
+        .collect();
+    assert_eq!(
+        chunks,
+        vec![
+            (OutputStream::Stdout, "out1\n".to_string()),
+            (OutputStream::Stderr, "err1\n".to_string()),
+            (OutputStream::Stdout, "out2\n".to_string()),
+        ]
+    );
+    assert!(transcript[0].time.unwrap() < transcript[2].time.unwrap());
+
+    let (res, transcript) = spawn_with_output!(bash -c "echo failed >&2; exit 3")
+        .unwrap()
+        .wait_with_transcript(false);
+    assert!(res.is_err());
+    assert_eq!(transcript.len(), 1);
+    assert_eq!(transcript[0].stream, OutputStream::Stderr);
+    assert_eq!(transcript[0].time, None);
+}
+
+#[test]
+fn test_wait_all() {
+    let outcomes = spawn!(bash -c "exit 3" | yes | head -n 1)
+        .unwrap()
+        .wait_all();
+    let statuses: Vec<_> = outcomes.iter().map(|x| x.status.clone()).collect();
+    assert_eq!(
+        statuses,
+        vec![
+            StageStatus::Exited(3),
+            StageStatus::Signaled(13),
+            StageStatus::Exited(0)
+        ]
+    );
+    assert_eq!(outcomes[0].cmd, r#""bash" "-c" "exit 3""#);
+    assert_eq!(outcomes[2].cmd, r#""head" "-n" "1""#);
+
+    fn fail(_env: &mut CmdEnv) -> CmdResult {
+        Err(std::io::Error::other("oops"))
+    }
+    use_custom_cmd!(fail);
+    let outcomes = spawn!(fail | cat).unwrap().wait_all();
+    assert!(matches!(&outcomes[0].status, StageStatus::Error(e) if e.contains("oops")));
+    assert!(outcomes[1].status.success());
+}
+
+#[test]
+fn test_usage() {
+    let mut proc =
+        spawn!(bash -c "sleep 0.1; head -c 10000000 /dev/zero > /dev/null" | cat).unwrap();
+    assert_eq!(proc.usage(), vec![None, None]);
+    proc.wait().unwrap();
+    let usage = proc.usage();
+    let first = usage[0].as_ref().unwrap();
+    assert!(first.wall_time >= std::time::Duration::from_millis(100));
+    if cfg!(target_os = "linux") {
+        assert!(first.rusage.as_ref().unwrap().max_rss > 0);
+    }
+
+    // each command is timed until it exited, not until the ones after it did
+    if cfg!(target_os = "linux") {
+        let mut proc = spawn!(sleep 0.05 | sleep 0.5).unwrap();
+        proc.wait().unwrap();
+        let usage: Vec<_> = proc.usage().into_iter().map(Option::unwrap).collect();
+        assert!(usage[0].wall_time < std::time::Duration::from_millis(300));
+        assert!(usage[1].wall_time >= std::time::Duration::from_millis(400));
+    }
+
+    fn noop(_env: &mut CmdEnv) -> CmdResult {
+        Ok(())
+    }
+    use_custom_cmd!(noop);
+    let outcomes = spawn!(noop | cat).unwrap().wait_all();
+    assert_eq!(outcomes[0].usage.rusage, None);
+    if cfg!(target_os = "linux") {
+        assert!(outcomes[1].usage.rusage.is_some());
+    }
+
+    // polled children keep their usage as well
+    let mut proc = spawn!(true).unwrap();
+    while proc.try_wait().is_none() {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert!(proc.usage()[0].is_some());
+
+    assert!(run_cmd!(time true | cat).is_ok());
+    assert!(run_cmd!(time false).is_err());
+
+    // options are for the external `time` command
+    let mock = Mock::new()
+        .on(["time", "**"], MockOutput::default())
+        .install();
+    assert!(run_cmd!(time -v true).is_ok());
+    assert_eq!(mock.calls(), vec![vec!["time", "-v", "true"]]);
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing() {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    #[derive(Default)]
+    struct Fields(HashMap<String, String>);
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name().into(), format!("{value:?}"));
+        }
+    }
+
+    thread_local! {
+        static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(vec![]) };
+    }
+
+    type Events = Vec<(Option<u64>, Fields)>;
+
+    // keeps the fields of all spans, and the events with the span they were emitted in
+    #[derive(Clone, Default)]
+    struct Collector {
+        spans: Arc<Mutex<Vec<Fields>>>,
+        events: Arc<Mutex<Events>>,
+    }
+    impl Subscriber for Collector {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut fields = Fields::default();
+            span.record(&mut fields);
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(fields);
+            Id::from_u64(spans.len() as u64)
+        }
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            values.record(&mut self.spans.lock().unwrap()[span.into_u64() as usize - 1]);
+        }
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            let span = ENTERED.with(|entered| entered.borrow().last().copied());
+            self.events.lock().unwrap().push((span, fields));
+        }
+        fn enter(&self, span: &Id) {
+            ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+        }
+        fn exit(&self, _: &Id) {
+            ENTERED.with(|entered| entered.borrow_mut().pop());
+        }
+    }
+
+    let collector = Collector::default();
+    tracing::subscriber::set_global_default(collector.clone()).unwrap();
+    let sink = StderrSink::Tracing(log::Level::Info);
+    let child = spawn!(bash -c "echo traced >&2; exit 7").unwrap();
+    assert!(child.stderr_sink(sink).wait().is_err());
+    spawn!(sleep 9.5).unwrap().kill().unwrap();
+
+    let spans = collector.spans.lock().unwrap();
+    let (i, span) = spans
+        .iter()
+        .enumerate()
+        .find(|(_, span)| span.0["cmd"].contains("echo traced"))
+        .unwrap();
+    assert_eq!(span.0["status"], "\"7\"");
+    assert!(span.0.contains_key("pids"));
+    assert!(span.0.contains_key("duration_ms"));
+    let events = collector.events.lock().unwrap();
+    let (event_span, _) = events
+        .iter()
+        .find(|(_, event)| event.0.get("message").is_some_and(|x| x == "traced"))
+        .unwrap();
+    assert_eq!(*event_span, Some(i as u64 + 1));
+
+    // killed pipelines are closed too
+    let span = spans
+        .iter()
+        .find(|span| span.0["cmd"].contains("9.5"))
+        .unwrap();
+    assert_eq!(span.0["status"], "\"killed\"");
+    assert!(span.0.contains_key("duration_ms"));
+}
+
+#[test]
+fn test_dry_run() {
+    let file = "/tmp/cmd_lib_test_dry_run.txt";
+    run_cmd!(rm -f $file).unwrap();
+    assert!(run_cmd!(dry_run touch $file).is_ok());
+    assert!(run_cmd!(dry_run echo hi > $file).is_ok());
+    assert!(!std::path::Path::new(file).exists());
+    assert!(run_cmd!(dry_run false).is_ok());
+
+    // captured output is empty, but side-effect-free builtins still run
+    assert_eq!(run_fun!(dry_run echo hi).unwrap(), "");
+    assert_eq!(run_fun!(dry_run echo hi | wc -c).unwrap(), "");
+    assert_eq!(run_fun!(dry_run cd /tmp; pwd).unwrap(), "/tmp");
+}
+
+#[test]
+fn test_mock() {
+    use std::io::{Read, Write};
+    let mock = Mock::new()
+        .on(
+            ["git", "rev-parse", "*"],
+            MockOutput::default().stdout("abc123\n"),
+        )
+        .on(
+            ["git", "**"],
+            MockOutput::default().stderr("fatal\n").exit_code(128),
+        )
+        .on_run(["shout"], |env| {
+            let mut input = String::new();
+            env.stdin().read_to_string(&mut input)?;
+            write!(env.stdout(), "{}", input.to_uppercase())
+        })
+        .install();
+    assert_eq!(run_fun!(git rev-parse HEAD).unwrap(), "abc123");
+    assert!(run_cmd!(git push origin).is_err());
+    assert_eq!(run_fun!(echo hi | shout).unwrap(), "HI");
+    assert_eq!(run_fun!(git rev-parse HEAD | wc -c).unwrap(), "7");
+    assert_eq!(
+        mock.calls(),
+        [
+            vec!["git", "rev-parse", "HEAD"],
+            vec!["git", "push", "origin"],
+            vec!["shout"],
+            vec!["git", "rev-parse", "HEAD"],
+            vec!["wc", "-c"],
+        ]
+    );
+
+    // commands after a failing one never start, so they aren't recorded
+    let later = Mock::new().install();
+    assert!(run_cmd!(false; git push).is_err());
+    assert_eq!(later.calls(), [vec!["false"]]);
+    drop(later);
+
+    // other threads and later commands run the real programs
+    std::thread::spawn(|| assert!(run_cmd!(shout).is_err()))
+        .join()
+        .unwrap();
+    drop(mock);
+    assert!(run_cmd!(shout).is_err());
+}
+
+#[test]
+fn test_transcript() {
+    let file = "/tmp/cmd_lib_test_transcript.txt";
+    let transcript = Transcript::record(file).unwrap();
+    let recorded = run_fun!(date +%s%N).unwrap();
+    assert_eq!(run_fun!(echo hello | tr a-z A-Z).unwrap(), "HELLO");
+    assert!(run_cmd!(bash -c "echo oops >&2; exit 3").is_err());
+    transcript.finish().unwrap();
+    let content = std::fs::read_to_string(file).unwrap();
+    assert!(content.starts_with("$ date +%s%N\nstdout "));
+    assert!(content.ends_with(
+        "$ tr a-z A-Z\nstdin 6\nhello\n\nstdout 6\nHELLO\n\nexit 0\n\n\
+         $ bash -c 'echo oops >&2; exit 3'\nstderr 5\noops\n\nexit 3\n\n"
+    ));
+
+    // replayed commands are not run
+    let transcript = Transcript::replay(file).unwrap();
+    assert_eq!(run_fun!(date +%s%N).unwrap(), recorded);
+    assert_eq!(run_fun!(echo hello | tr a-z A-Z).unwrap(), "HELLO");
+    let err = run_cmd!(bash -c "exit 0").unwrap_err();
+    assert!(err.to_string().contains("differs from command #3"));
+    assert!(transcript.finish().is_err());
+
+    let transcript = Transcript::replay(file).unwrap();
+    assert!(run_fun!(date +%s%N).is_ok());
+    assert!(run_fun!(echo bye | tr a-z A-Z).is_err());
+    drop(transcript);
+    run_cmd!(rm -f $file).unwrap();
+}
+
+#[test]
+fn test_xtrace() {
+    let file = "/tmp/cmd_lib_test_xtrace.txt";
+    run_cmd!(rm -f $file).unwrap();
+    // trace through a session, other tests run in parallel with the global setting
+    let mut sh = Shell::new();
+    sh.set_xtrace(Xtrace::File(file.into()));
+    let res = run_cmd!(in sh; cd /tmp; XTRACE_TEST=1 bash -c "exit 0" | bash -c "exit 4");
+    let mut piped = spawn_with_output!(in sh; XTRACE_PIPE=1 yes).unwrap();
+    let mut killed = spawn!(in sh; XTRACE_KILL=1 sleep 10).unwrap();
+    assert!(res.is_err());
+    piped.wait_with_pipe(&mut |_| {}).unwrap();
+    killed.kill().unwrap();
+
+    let cmd = r#"XTRACE_TEST="1" "bash" "-c" "exit 0" | "bash" "-c" "exit 4""#;
+    let content = std::fs::read_to_string(file).unwrap();
+    let lines: Vec<&str> = content.lines().filter(|x| x.contains(cmd)).collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("+ "));
+    assert!(lines[0].contains(&format!("start [{cmd}] in /tmp at tests/test_macros.rs:")));
+    assert!(lines[1].contains(&format!("end [{cmd}] status 0 | 4 in ")));
+
+    // pipelines not waited for to the end are traced as well
+    let end = |cmd: &str| {
+        content
+            .lines()
+            .any(|x| x.contains(&format!("end [{cmd}] status ")))
+    };
+    assert!(end(r#"XTRACE_PIPE="1" "yes""#));
+    assert!(end(r#"XTRACE_KILL="1" "sleep" "10""#));
+    run_cmd!(rm -f $file).unwrap();
+}
+
+#[test]
+fn test_shell() {
+    use std::io::Write;
+    let mut sh = Shell::new();
+    let mut other = Shell::new();
+    sh.set_var("SHELL_TEST", "1");
+    sh.register_cmd("greet", |env| {
+        let name = env.get_args()[0].clone();
+        writeln!(env.stdout(), "hi from {name}")
+    });
+    sh.register_cmd("echo", |env| writeln!(env.stdout(), "echo from sh"));
+    assert_eq!(run_fun!(in sh; echo hi).unwrap(), "echo from sh");
+    assert_eq!(run_fun!(in other; echo hi).unwrap(), "hi");
+    run_cmd!(in sh; cd /tmp).unwrap();
+    run_cmd!(in other; cd /).unwrap();
+    assert_eq!(run_fun!(in sh; pwd).unwrap(), "/tmp");
+    assert_eq!(run_fun!(in other; pwd).unwrap(), "/");
+    assert_eq!(sh.current_dir(), std::path::Path::new("/tmp"));
+    assert_eq!(run_fun!(in sh; printenv SHELL_TEST).unwrap(), "1");
+    assert_eq!(
+        run_fun!(in sh; SHELL_TEST=2 printenv SHELL_TEST).unwrap(),
+        "2"
+    );
+    assert!(run_fun!(in other; printenv SHELL_TEST).is_err());
+    assert_eq!(run_fun!(in sh; greet sh).unwrap(), "hi from sh");
+    assert!(run_cmd!(in other; greet other).is_err());
+
+    sh.set_pipefail(false);
+    assert!(run_cmd!(in sh; false | true).is_ok());
+    assert!(run_cmd!(in other; false | true).is_err());
+    sh.set_dry_run(true);
+    assert_eq!(run_fun!(in sh; printenv SHELL_TEST).unwrap(), "");
+
+    let shell = &mut other;
+    assert_eq!(
+        spawn_with_output!(in shell; pwd)
+            .unwrap()
+            .wait_with_output()
+            .unwrap(),
+        "/"
+    );
+}
+
+#[test]
+fn test_dir_stack() {
+    assert_eq!(
+        run_fun! {
+            cd /;
+            pushd /tmp;
+            pushd /usr;
+            dirs;
+        }
+        .unwrap(),
+        "/usr /tmp /"
+    );
+    assert_eq!(run_fun!(cd /; pushd /tmp; popd; pwd).unwrap(), "/");
+    assert!(run_cmd!(popd).is_err());
+    assert!(run_cmd!(pushd / bad_dir).is_err());
+
+    let mut sh = Shell::new();
+    run_cmd!(in sh; pushd /tmp).unwrap();
+    assert_eq!(run_fun!(in sh; pwd).unwrap(), "/tmp");
+    run_cmd!(in sh; popd).unwrap();
+    assert!(run_cmd!(in sh; popd).is_err());
+
+    let dir = with_dir("/tmp", || {
+        let pwd = run_fun!(pwd).unwrap();
+        assert_eq!(with_dir("..", || run_fun!(pwd)).unwrap(), "/");
+        assert_eq!(run_fun!(in sh; pwd).unwrap(), "/tmp");
+        pwd
+    });
+    assert_eq!(dir, "/tmp");
+    assert_eq!(
+        run_fun!(pwd).unwrap(),
+        std::env::current_dir().unwrap().to_string_lossy()
+    );
+}
+
+#[test]
+fn test_env_prefix() {
+    use std::io::Write;
+    std::env::set_var("ENV_PREFIX_TEST", "1");
+    assert_eq!(run_fun!(printenv ENV_PREFIX_TEST).unwrap(), "1");
+    assert!(run_fun!(env -u ENV_PREFIX_TEST printenv ENV_PREFIX_TEST).is_err());
+    assert!(run_fun!(env -i /usr/bin/printenv ENV_PREFIX_TEST).is_err());
+    assert_eq!(
+        run_fun!(env -i FOO=bar /usr/bin/printenv).unwrap(),
+        "FOO=bar"
+    );
+    assert_eq!(run_fun!(env -C /tmp pwd).unwrap(), "/tmp");
+    assert_eq!(run_fun!(cd /; env -C tmp pwd; pwd).unwrap(), "/");
+    assert_eq!(run_fun!(env -i FOO=bar env).unwrap(), "FOO=bar");
+    std::env::set_var("ENV_PREFIX_KEEP", "2");
+    assert_eq!(
+        run_fun!(env -k ENV_PREFIX_KEEP -k ENV_PREFIX_MISSING FOO=bar /usr/bin/printenv).unwrap(),
+        "ENV_PREFIX_KEEP=2\nFOO=bar"
+    );
+    // without a command to run, the kept variables are passed to the external `env`
+    assert_eq!(
+        run_fun!(env -k ENV_PREFIX_KEEP -u FOO).unwrap(),
+        "ENV_PREFIX_KEEP=2"
+    );
+    assert!(run_fun!(env | grep ENV_PREFIX_TEST).is_ok());
+
+    let mut sh = Shell::new();
+    sh.set_var("SESSION_VAR", "1");
+    sh.register_cmd("show_env", |env| {
+        let var = env.var("SESSION_VAR").cloned().unwrap_or_default();
+        let dir = env.current_dir().display().to_string();
+        writeln!(env.stdout(), "{var} {dir}")
+    });
+    assert_eq!(
+        run_fun!(in sh; show_env).unwrap().split(' ').next(),
+        Some("1")
+    );
+    assert_eq!(run_fun!(in sh; env -i -C /tmp show_env).unwrap(), " /tmp");
+    assert_eq!(
+        run_fun!(in sh; env -k ENV_PREFIX_KEEP -C /tmp show_env).unwrap(),
+        " /tmp"
+    );
+    assert!(run_fun!(in sh; env -u SESSION_VAR printenv SESSION_VAR).is_err());
+}
+
+#[test]
+fn test_register_cmd_fn() {
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let count = Arc::new(AtomicUsize::new(0));
+    let counter = count.clone();
+    register_cmd_fn("count_calls", move |env| {
+        let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        writeln!(env.stdout(), "{n}")
+    });
+    run_cmd!(count_calls; count_calls).unwrap();
+    assert_eq!(run_fun!(count_calls).unwrap(), "3");
+    assert_eq!(count.load(Ordering::SeqCst), 3);
+
+    {
+        let _guard = register_scoped_cmd("count_calls", |env| writeln!(env.stdout(), "scoped"));
+        assert_eq!(run_fun!(count_calls).unwrap(), "scoped");
+        let _inner = register_scoped_cmd("scoped_only", |_| Ok(()));
+        assert!(run_cmd!(scoped_only).is_ok());
+    }
+    assert!(run_cmd!(scoped_only).is_err());
+    assert_eq!(run_fun!(count_calls).unwrap(), "4");
+}